@@ -0,0 +1,99 @@
+use super::{repeats, Effect, Line};
+use std::f64::consts::PI;
+
+// Delay line swept by a sine. Short delays with feedback give a flanger,
+// longer ones without feedback a chorus. Times are in seconds.
+pub struct Chorus {
+    delay: f64,
+    depth: f64,
+    rate: f64,
+    feedback: f64,
+    mix: f64,
+    line: Line,
+    position: usize,
+}
+
+impl Chorus {
+    pub fn new(delay: f64, depth: f64, rate: f64, feedback: f64) -> Self {
+        Self {
+            delay: delay.max(0.0),
+            depth: depth.clamp(0.0, delay.max(0.0)),
+            rate,
+            feedback: feedback.clamp(0.0, 0.99),
+            mix: 0.5,
+            line: Line::default(),
+            position: 0,
+        }
+    }
+
+    pub fn flanger() -> Self {
+        Self::new(0.003, 0.002, 0.25, 0.6)
+    }
+
+    pub fn with_mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+}
+
+impl Default for Chorus {
+    fn default() -> Self {
+        Self::new(0.02, 0.005, 0.8, 0.0)
+    }
+}
+
+impl Effect for Chorus {
+    fn process(&mut self, buffer: &mut [f64], sample_rate: i32) {
+        let rate = sample_rate as f64;
+        self.line
+            .reserve(((self.delay + self.depth) * rate).ceil() as usize + 2);
+        for x in buffer.iter_mut() {
+            let t = self.position as f64 / rate;
+            let sweep = (2.0 * PI * self.rate * t).sin();
+            let wet = self.line.read((self.delay + self.depth * sweep) * rate);
+            self.line.write(*x + wet * self.feedback);
+            *x = *x * (1.0 - self.mix) + wet * self.mix;
+            self.position += 1;
+        }
+    }
+
+    fn tail(&self) -> f64 {
+        (self.delay + self.depth) * repeats(self.feedback)
+    }
+
+    fn fresh(&self) -> Box<dyn Effect> {
+        let fresh = Self::new(self.delay, self.depth, self.rate, self.feedback);
+        Box::new(fresh.with_mix(self.mix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweeps_delay_time() {
+        let rate = 44100;
+        let steady: Vec<f64> = (0..rate)
+            .map(|i| (2.0 * PI * 440.0 * i as f64 / rate as f64).sin())
+            .collect();
+
+        let mut swept = steady.clone();
+        Chorus::default().with_mix(1.0).process(&mut swept, rate);
+        let mut fixed = steady.clone();
+        Chorus::new(0.02, 0.0, 0.8, 0.0)
+            .with_mix(1.0)
+            .process(&mut fixed, rate);
+
+        // Fixed delay is just the input shifted, the swept one isn't
+        let shift = crate::samples(0.02, rate);
+        let error = |x: &[f64]| {
+            x[shift..]
+                .iter()
+                .zip(steady.iter())
+                .fold(0.0, |e: f64, (a, b)| e.max((a - b).abs()))
+        };
+        assert!(error(&fixed) < 1e-9);
+        assert!(error(&swept) > 0.5);
+    }
+}
@@ -0,0 +1,64 @@
+use super::{repeats, Effect, Line};
+
+// Feedback echo. Mix is the wet/dry balance, 0.0 being dry only.
+pub struct Delay {
+    time: f64,
+    feedback: f64,
+    mix: f64,
+    line: Line,
+}
+
+impl Delay {
+    pub fn new(time: f64, feedback: f64) -> Self {
+        Self {
+            time: time.max(0.0),
+            feedback: feedback.clamp(0.0, 0.99),
+            mix: 0.5,
+            line: Line::default(),
+        }
+    }
+
+    pub fn with_mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, buffer: &mut [f64], sample_rate: i32) {
+        let delay = (self.time * sample_rate as f64).max(1.0);
+        self.line.reserve(delay.ceil() as usize + 2);
+        for x in buffer.iter_mut() {
+            let wet = self.line.read(delay);
+            self.line.write(*x + wet * self.feedback);
+            *x = *x * (1.0 - self.mix) + wet * self.mix;
+        }
+    }
+
+    fn tail(&self) -> f64 {
+        self.time * repeats(self.feedback)
+    }
+
+    fn fresh(&self) -> Box<dyn Effect> {
+        Box::new(Self::new(self.time, self.feedback).with_mix(self.mix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echoes_after_delay() {
+        let mut delay = Delay::new(0.01, 0.5).with_mix(1.0);
+        let mut buffer = vec![0.0; 1000];
+        buffer[0] = 1.0;
+        delay.process(&mut buffer, 10000);
+
+        assert_eq!(0.0, buffer[0]);
+        assert!((1.0 - buffer[100]).abs() < 1e-9);
+        assert!((0.5 - buffer[200]).abs() < 1e-9);
+        assert!((0.25 - buffer[300]).abs() < 1e-9);
+        assert!((0.1 - delay.tail()).abs() < 1e-9);
+    }
+}
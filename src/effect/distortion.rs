@@ -0,0 +1,71 @@
+use super::Effect;
+use std::f64::consts::PI;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shape {
+    Soft,
+    Hard,
+    Fold,
+}
+
+impl Shape {
+    pub fn apply(&self, x: f64) -> f64 {
+        match self {
+            Shape::Soft => x.tanh(),
+            Shape::Hard => x.clamp(-1.0, 1.0),
+            Shape::Fold => (x * PI / 2.0).sin(),
+        }
+    }
+}
+
+// Memoryless waveshaper, drive being the gain going into the shape
+pub struct Distortion {
+    shape: Shape,
+    drive: f64,
+    mix: f64,
+}
+
+impl Distortion {
+    pub fn new(shape: Shape, drive: f64) -> Self {
+        Self {
+            shape,
+            drive: drive.max(0.0),
+            mix: 1.0,
+        }
+    }
+
+    pub fn with_mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+}
+
+impl Effect for Distortion {
+    fn process(&mut self, buffer: &mut [f64], _sample_rate: i32) {
+        for x in buffer.iter_mut() {
+            let wet = self.shape.apply(*x * self.drive);
+            *x = *x * (1.0 - self.mix) + wet * self.mix;
+        }
+    }
+
+    fn fresh(&self) -> Box<dyn Effect> {
+        Box::new(Self::new(self.shape, self.drive).with_mix(self.mix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_stay_bounded() {
+        for shape in [Shape::Soft, Shape::Hard, Shape::Fold] {
+            let mut buffer: Vec<f64> = (0..100).map(|i| (i as f64 - 50.0) / 50.0).collect();
+            Distortion::new(shape, 10.0).process(&mut buffer, 44100);
+            assert!(buffer.iter().all(|x| x.abs() <= 1.0), "{:?}", shape);
+        }
+        assert!((Shape::Hard.apply(0.5) - 0.5).abs() < 1e-9);
+        assert!(Shape::Soft.apply(0.5) < 0.5);
+        assert!(Shape::Fold.apply(1.5) < Shape::Fold.apply(1.0));
+    }
+}
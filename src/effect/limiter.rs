@@ -0,0 +1,148 @@
+use super::Effect;
+use std::collections::VecDeque;
+
+// Look-ahead peak limiter. The sound comes out delayed by the look-ahead
+// time, during which the gain has already eased down for any peak that is
+// on its way, so nothing gets past the threshold. Release is how long the
+// gain takes to recover. Stereo pairs share one gain, taken from the louder
+// channel, so limiting doesn't shift the image.
+#[derive(Clone)]
+pub struct Limiter {
+    threshold: f64,
+    lookahead: f64,
+    release: f64,
+    delayed: VecDeque<(f64, f64)>,
+    required: VecDeque<f64>,
+    window: VecDeque<f64>,
+    sum: f64,
+    gain: f64,
+}
+
+impl Limiter {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold: threshold.max(1e-6),
+            lookahead: 0.005,
+            release: 0.05,
+            delayed: VecDeque::new(),
+            required: VecDeque::new(),
+            window: VecDeque::new(),
+            sum: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    pub fn with_lookahead(mut self, secs: f64) -> Self {
+        self.lookahead = secs.max(0.0);
+        self
+    }
+
+    pub fn with_release(mut self, secs: f64) -> Self {
+        self.release = secs.max(0.0);
+        self
+    }
+
+    // Delay between input and output, in frames
+    pub fn latency(&self, sample_rate: i32) -> usize {
+        crate::samples(self.lookahead, sample_rate).max(1)
+    }
+
+    pub fn process_stereo(&mut self, left: &mut [f64], right: &mut [f64], sample_rate: i32) {
+        let (frames, recovery) = self.timing(sample_rate);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            (*l, *r) = self.tick((*l, *r), frames, recovery);
+        }
+    }
+
+    // Look-ahead in frames, and how much of the way back to full gain each
+    // frame recovers
+    fn timing(&self, sample_rate: i32) -> (usize, f64) {
+        let recovery = match crate::samples(self.release, sample_rate) {
+            0 => 1.0,
+            release => 1.0 / release as f64,
+        };
+        (self.latency(sample_rate), recovery)
+    }
+
+    fn tick(&mut self, frame: (f64, f64), frames: usize, recovery: f64) -> (f64, f64) {
+        // Gain needed for this very sample, then the lowest of those over
+        // the look-ahead, then averaged so the gain ramps instead of jumping.
+        let peak = frame.0.abs().max(frame.1.abs());
+        let needed = (self.threshold / peak).min(1.0);
+        self.required.push_back(needed);
+        if self.required.len() > frames + 1 {
+            self.required.pop_front();
+        }
+        let lowest = self.required.iter().fold(1.0, |a: f64, &b| a.min(b));
+        self.window.push_back(lowest);
+        self.sum += lowest;
+        if self.window.len() > frames {
+            self.sum -= self.window.pop_front().unwrap_or_default();
+        }
+        let target = self.sum / self.window.len() as f64;
+
+        self.gain = if target < self.gain {
+            target
+        } else {
+            self.gain + (target - self.gain) * recovery
+        };
+        self.delayed.push_back(frame);
+        let (left, right) = if self.delayed.len() > frames {
+            self.delayed.pop_front().unwrap_or_default()
+        } else {
+            (0.0, 0.0)
+        };
+        (left * self.gain, right * self.gain)
+    }
+}
+
+impl Effect for Limiter {
+    fn process(&mut self, buffer: &mut [f64], sample_rate: i32) {
+        let (frames, recovery) = self.timing(sample_rate);
+        buffer
+            .iter_mut()
+            .for_each(|x| *x = self.tick((*x, *x), frames, recovery).0);
+    }
+
+    fn tail(&self) -> f64 {
+        self.lookahead
+    }
+
+    fn fresh(&self) -> Box<dyn Effect> {
+        let fresh = Self::new(self.threshold)
+            .with_lookahead(self.lookahead)
+            .with_release(self.release);
+        Box::new(fresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn peaks_stay_under_threshold() {
+        let rate = 44100;
+        let mut sound: Vec<f64> = (0..rate)
+            .map(|i| {
+                let t = i as f64 / rate as f64;
+                let level = if (0.3..0.6).contains(&t) { 3.0 } else { 0.5 };
+                level * (2.0 * PI * 220.0 * t).sin()
+            })
+            .collect();
+        let dry = sound.clone();
+        let mut limiter = Limiter::new(0.9);
+        let latency = limiter.latency(rate);
+        limiter.process(&mut sound, rate);
+
+        assert!(sound.iter().all(|x| x.abs() <= 0.9 + 1e-9));
+        assert!(sound.iter().any(|x| x.abs() > 0.85));
+        // Quiet parts come through untouched once the gain has recovered
+        for i in latency..crate::samples(0.25, rate) {
+            assert!((dry[i - latency] - sound[i]).abs() < 1e-9);
+        }
+        let recovered = crate::samples(0.95, rate);
+        assert!((dry[recovered - latency] - sound[recovered]).abs() < 1e-3);
+    }
+}
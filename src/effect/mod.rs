@@ -0,0 +1,124 @@
+pub mod chorus;
+pub mod delay;
+pub mod distortion;
+pub mod limiter;
+pub mod reverb;
+
+pub use chorus::Chorus;
+pub use delay::Delay;
+pub use distortion::{Distortion, Shape};
+pub use limiter::Limiter;
+pub use reverb::Reverb;
+
+// Insert effect processing a stream block by block. Effects carry their own
+// state (delay lines and such), so every stream works on a fresh copy.
+pub trait Effect {
+    fn process(&mut self, buffer: &mut [f64], sample_rate: i32);
+
+    // How long the effect keeps sounding after its input goes silent
+    fn tail(&self) -> f64 {
+        0.0
+    }
+
+    fn fresh(&self) -> Box<dyn Effect>;
+}
+
+// Effects applied one after another, in the order they were added
+#[derive(Default)]
+pub struct Effects {
+    chain: Vec<Box<dyn Effect>>,
+}
+
+impl Effects {
+    pub fn push(&mut self, effect: Box<dyn Effect>) {
+        self.chain.push(effect);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    pub fn tail(&self) -> f64 {
+        self.chain.iter().map(|x| x.tail()).sum()
+    }
+
+    pub fn fresh(&self) -> Self {
+        Self {
+            chain: self.chain.iter().map(|x| x.fresh()).collect(),
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [f64], sample_rate: i32) {
+        self.chain
+            .iter_mut()
+            .for_each(|x| x.process(buffer, sample_rate));
+    }
+}
+
+// Number of echoes before a feedback loop decays below -60dB
+fn repeats(feedback: f64) -> f64 {
+    if feedback <= 0.0 {
+        return 1.0;
+    }
+    (0.001_f64.ln() / feedback.ln()).ceil().max(1.0)
+}
+
+// Ring buffer read at fractional delays, allocated on first use
+#[derive(Default)]
+struct Line {
+    buffer: Vec<f64>,
+    head: usize,
+}
+
+impl Line {
+    fn reserve(&mut self, len: usize) {
+        if self.buffer.len() < len {
+            self.buffer = vec![0.0; len];
+            self.head = 0;
+        }
+    }
+
+    fn write(&mut self, x: f64) {
+        self.buffer[self.head] = x;
+        self.head = (self.head + 1) % self.buffer.len();
+    }
+
+    // `delay` samples behind the last write, 1.0 being the last sample
+    fn read(&self, delay: f64) -> f64 {
+        let len = self.buffer.len();
+        let at = (self.head + len) as f64 - delay.clamp(1.0, len as f64 - 1.0);
+        let i = at.floor() as usize;
+        let frac = at - at.floor();
+        let a = self.buffer[i % len];
+        let b = self.buffer[(i + 1) % len];
+        a + (b - a) * frac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_reads_back_writes() {
+        let mut line = Line::default();
+        line.reserve(8);
+        (1..=5).for_each(|x| line.write(x as f64));
+
+        assert_eq!(5.0, line.read(1.0));
+        assert_eq!(3.0, line.read(3.0));
+        assert_eq!(3.5, line.read(2.5));
+    }
+
+    #[test]
+    fn chain_runs_in_order() {
+        let mut effects = Effects::default();
+        effects.push(Box::new(Distortion::new(Shape::Hard, 4.0)));
+        effects.push(Box::new(Delay::new(0.001, 0.0).with_mix(0.5)));
+
+        let mut buffer = vec![1.0; 100];
+        effects.process(&mut buffer, 44100);
+        assert!(buffer.iter().all(|&x| x <= 1.0));
+        assert!((0.001 - effects.tail()).abs() < 1e-9);
+    }
+}
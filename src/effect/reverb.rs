@@ -0,0 +1,108 @@
+use super::{Effect, Line};
+
+// Comb and allpass lengths from Freeverb, in samples at 44.1kHz
+const COMBS: [f64; 4] = [1116.0, 1188.0, 1277.0, 1356.0];
+const ALLPASSES: [f64; 2] = [556.0, 441.0];
+const INPUT_GAIN: f64 = 0.05;
+
+// Schroeder-style algorithmic reverb: parallel damped combs into a couple
+// of series allpasses. Room size goes from 0.0 to 1.0, as does damping.
+pub struct Reverb {
+    room: f64,
+    damping: f64,
+    mix: f64,
+    combs: Vec<(Line, f64)>,
+    allpasses: Vec<Line>,
+}
+
+impl Reverb {
+    pub fn new(room: f64, damping: f64) -> Self {
+        Self {
+            room: room.clamp(0.0, 1.0),
+            damping: damping.clamp(0.0, 1.0),
+            mix: 0.3,
+            combs: Vec::new(),
+            allpasses: Vec::new(),
+        }
+    }
+
+    pub fn with_mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+
+    fn feedback(&self) -> f64 {
+        0.7 + 0.28 * self.room
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, buffer: &mut [f64], sample_rate: i32) {
+        let scale = sample_rate as f64 / 44100.0;
+        if self.combs.is_empty() {
+            self.combs = COMBS
+                .iter()
+                .map(|x| {
+                    let mut line = Line::default();
+                    line.reserve((x * scale) as usize + 1);
+                    (line, 0.0)
+                })
+                .collect();
+            self.allpasses = ALLPASSES
+                .iter()
+                .map(|x| {
+                    let mut line = Line::default();
+                    line.reserve((x * scale) as usize + 1);
+                    line
+                })
+                .collect();
+        }
+
+        let feedback = self.feedback();
+        for x in buffer.iter_mut() {
+            let input = *x * INPUT_GAIN;
+            let mut wet = 0.0;
+            for ((line, store), length) in self.combs.iter_mut().zip(COMBS.iter()) {
+                let out = line.read(length * scale);
+                *store = out * (1.0 - self.damping) + *store * self.damping;
+                line.write(input + *store * feedback);
+                wet += out;
+            }
+            for (line, length) in self.allpasses.iter_mut().zip(ALLPASSES.iter()) {
+                let out = line.read(length * scale);
+                line.write(wet + out * 0.5);
+                wet = out - wet;
+            }
+            *x = *x * (1.0 - self.mix) + wet * self.mix;
+        }
+    }
+
+    // Time for the longest comb to ring down by 60dB
+    fn tail(&self) -> f64 {
+        let longest = COMBS[COMBS.len() - 1] / 44100.0;
+        -3.0 * longest / self.feedback().log10()
+    }
+
+    fn fresh(&self) -> Box<dyn Effect> {
+        Box::new(Self::new(self.room, self.damping).with_mix(self.mix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rings_then_dies_out() {
+        let rate = 44100;
+        let mut reverb = Reverb::new(0.5, 0.3).with_mix(1.0);
+        let tail = crate::samples(reverb.tail(), rate);
+        let mut buffer = vec![0.0; tail + rate as usize];
+        buffer[..100].fill(1.0);
+        reverb.process(&mut buffer, rate);
+
+        let peak = |x: &[f64]| x.iter().fold(0.0, |x: f64, y| x.max(y.abs()));
+        assert!(peak(&buffer[..tail / 4]) > 0.01);
+        assert!(peak(&buffer[tail..]) < peak(&buffer[..tail / 4]) / 100.0);
+    }
+}
@@ -0,0 +1,183 @@
+use super::curve::Curve;
+use super::Envelope;
+
+// Arbitrary envelope made of (time, level, curve) points, where the curve
+// shapes the segment leading into its point. The envelope starts from
+// silence and holds the last level once all the points have passed.
+//
+// With a loop set, the segment between the loop points repeats for as long
+// as the note is held; on note-off the remaining points after the loop end
+// play out as the release. Either way, a short fade at the very end keeps
+// the note from cutting off with a click.
+#[derive(Debug)]
+pub struct Breakpoint {
+    points: Vec<(f64, f64, Curve)>,
+    looping: Option<(usize, usize)>,
+}
+
+// Length of the fade-out closing every release
+const DECLICK: f64 = 0.005;
+
+impl Breakpoint {
+    pub fn new(mut points: Vec<(f64, f64, Curve)>) -> Self {
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self {
+            points,
+            looping: None,
+        }
+    }
+
+    pub fn linear(points: Vec<(f64, f64)>) -> Self {
+        Self::new(
+            points
+                .into_iter()
+                .map(|(t, level)| (t, level, Curve::Linear))
+                .collect(),
+        )
+    }
+
+    // Loop points are indices into the list of points; invalid ranges are ignored
+    pub fn with_loop(mut self, start: usize, end: usize) -> Self {
+        self.looping = if start < end && end < self.points.len() {
+            Some((start, end))
+        } else {
+            None
+        };
+        self
+    }
+
+    fn looped(&self, t: f64) -> f64 {
+        if let Some((start, end)) = self.looping {
+            let start = self.points[start].0;
+            let end = self.points[end].0;
+            if t > end && end > start {
+                return start + (t - start) % (end - start);
+            }
+        }
+        t
+    }
+
+    fn level_from(&self, t: f64, from: (f64, f64), points: &[(f64, f64, Curve)]) -> f64 {
+        let mut prev = from;
+        for &(time, level, curve) in points {
+            if t < time {
+                let span = time - prev.0;
+                let x = if span > 0.0 { (t - prev.0) / span } else { 1.0 };
+                return prev.1 + (level - prev.1) * curve.apply(x);
+            }
+            prev = (time, level);
+        }
+        prev.1
+    }
+}
+
+impl Envelope for Breakpoint {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        volume * self.level_from(self.looped(t), (0.0, 0.0), &self.points)
+    }
+
+    fn min(&self) -> f64 {
+        self.points.last().map_or(0.0, |x| x.0)
+    }
+
+    fn release(&self) -> f64 {
+        let tail = match self.looping {
+            Some((_, end)) => self.min() - self.points[end].0,
+            None => 0.0,
+        };
+        tail + DECLICK
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        if t < off {
+            return self.value_at(t, volume);
+        }
+        let tail = self.release() - DECLICK;
+        let level = match self.looping {
+            Some((_, end)) => {
+                let from = self.points[end].0;
+                let level = self.value_at(off, 1.0);
+                let points = &self.points[end + 1..];
+                self.level_from(from + (t - off).min(tail), (from, level), points)
+            }
+            None => self.value_at(off, 1.0),
+        };
+        let fade = (off + tail + DECLICK - t) / DECLICK;
+        volume * level * fade.clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::assert_close;
+
+    #[test]
+    fn follows_points() {
+        let env = Breakpoint::linear(vec![(0.5, 0.2), (0.1, 1.0), (0.3, 0.4)]);
+
+        assert_close(0.0, env.value_at(0.0, 1.0));
+        assert_close(0.5, env.value_at(0.05, 1.0));
+        assert_close(0.7, env.value_at(0.2, 1.0));
+        assert_close(0.3, env.value_at(0.4, 1.0));
+        assert_close(0.1, env.value_at(0.6, 0.5));
+        assert_close(0.5, env.min());
+        assert_close(DECLICK, env.release());
+    }
+
+    #[test]
+    fn segments_take_curves() {
+        let env = Breakpoint::new(vec![(1.0, 1.0, Curve::Exponential(4.0))]);
+        assert_close(Curve::Exponential(4.0).apply(0.25), env.value_at(0.25, 1.0));
+    }
+
+    #[test]
+    fn loops_while_held() {
+        let env = Breakpoint::linear(vec![(0.1, 1.0), (0.2, 0.5), (0.3, 1.0), (0.5, 0.0)])
+            .with_loop(0, 2);
+
+        assert_close(0.75, env.value_at(0.15, 1.0));
+        assert_close(0.75, env.value_at(0.35, 1.0));
+        assert_close(0.5, env.value_at(0.4, 1.0));
+        assert_close(0.2 + DECLICK, env.release());
+    }
+
+    #[test]
+    fn releases_after_loop_end() {
+        let env = Breakpoint::linear(vec![(0.1, 1.0), (0.2, 0.5), (0.3, 1.0), (0.5, 0.0)])
+            .with_loop(0, 2);
+
+        assert_close(0.5, env.released_at(1.0, 1.0, 1.0));
+        assert_close(0.25, env.released_at(1.1, 1.0, 1.0));
+        assert_close(0.0, env.released_at(1.2, 1.0, 1.0));
+        assert_close(0.0, env.released_at(2.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn fades_out_without_loop() {
+        let env = Breakpoint::linear(vec![(0.1, 1.0), (0.3, 0.5)]);
+
+        assert_close(0.75, env.released_at(0.2, 0.2, 1.0));
+        assert_close(0.375, env.released_at(0.2 + DECLICK / 2.0, 0.2, 1.0));
+        assert_close(0.0, env.released_at(0.2 + DECLICK, 0.2, 1.0));
+        assert_close(0.0, env.released_at(1.0, 0.2, 1.0));
+
+        // A loop whose tail ends above zero fades out after it
+        let env = Breakpoint::linear(vec![(0.1, 1.0), (0.2, 0.5), (0.3, 0.8)]).with_loop(0, 1);
+        assert_close(0.8, env.released_at(1.1, 1.0, 1.0));
+        assert_close(0.4, env.released_at(1.1 + DECLICK / 2.0, 1.0, 1.0));
+        assert_close(0.0, env.released_at(1.1 + DECLICK, 1.0, 1.0));
+    }
+
+    #[test]
+    fn works_as_elfo_cycle() {
+        use crate::lfo::ELFO;
+        use crate::Signal;
+
+        let elfo = ELFO::square(10.0).with_envelope(Breakpoint::linear(vec![(0.2, 1.0)]));
+        assert_close(
+            elfo.value_at(0.01, 0.0, 44100),
+            elfo.value_at(0.21, 0.0, 44100),
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use super::Envelope;
+
+pub struct Product {
+    a: Box<dyn Envelope>,
+    b: Box<dyn Envelope>,
+}
+
+impl Product {
+    pub fn new(a: Box<dyn Envelope>, b: Box<dyn Envelope>) -> Self {
+        Self { a, b }
+    }
+}
+
+impl Envelope for Product {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        self.a.value_at(t, volume) * self.b.value_at(t, 1.0)
+    }
+
+    fn min(&self) -> f64 {
+        self.a.min().max(self.b.min())
+    }
+
+    fn release(&self) -> f64 {
+        self.a.release().min(self.b.release())
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        self.a.released_at(t, off, volume) * self.b.released_at(t, off, 1.0)
+    }
+}
+
+pub struct Sum {
+    a: Box<dyn Envelope>,
+    b: Box<dyn Envelope>,
+}
+
+impl Sum {
+    pub fn new(a: Box<dyn Envelope>, b: Box<dyn Envelope>) -> Self {
+        Self { a, b }
+    }
+}
+
+impl Envelope for Sum {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        self.a.value_at(t, volume) + self.b.value_at(t, volume)
+    }
+
+    fn min(&self) -> f64 {
+        self.a.min().max(self.b.min())
+    }
+
+    fn release(&self) -> f64 {
+        self.a.release().max(self.b.release())
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        self.a.released_at(t, off, volume) + self.b.released_at(t, off, volume)
+    }
+}
+
+// Plays the second envelope once the first one has run its course
+pub struct Sequence {
+    first: Box<dyn Envelope>,
+    second: Box<dyn Envelope>,
+}
+
+impl Sequence {
+    pub fn new(first: Box<dyn Envelope>, second: Box<dyn Envelope>) -> Self {
+        Self { first, second }
+    }
+}
+
+impl Envelope for Sequence {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        let switch = self.first.min();
+        if t < switch {
+            self.first.value_at(t, volume)
+        } else {
+            self.second.value_at(t - switch, volume)
+        }
+    }
+
+    fn min(&self) -> f64 {
+        self.first.min() + self.second.min()
+    }
+
+    fn release(&self) -> f64 {
+        self.first.release().max(self.second.release())
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        let switch = self.first.min();
+        if off < switch {
+            self.first.released_at(t, off, volume)
+        } else if t < switch {
+            self.first.value_at(t, volume)
+        } else {
+            self.second.released_at(t - switch, off - switch, volume)
+        }
+    }
+}
+
+// Time-scaling: factor of 2.0 makes every stage twice as long
+pub struct Stretch {
+    inner: Box<dyn Envelope>,
+    factor: f64,
+}
+
+// Shortest stretch allowed, zero or less would stop or reverse time
+const MIN_STRETCH: f64 = 1e-3;
+
+impl Stretch {
+    pub fn new(inner: Box<dyn Envelope>, factor: f64) -> Self {
+        Self {
+            inner,
+            factor: factor.max(MIN_STRETCH),
+        }
+    }
+}
+
+impl Envelope for Stretch {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        self.inner.value_at(t / self.factor, volume)
+    }
+
+    fn min(&self) -> f64 {
+        self.inner.min() * self.factor
+    }
+
+    fn release(&self) -> f64 {
+        self.inner.release() * self.factor
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        self.inner
+            .released_at(t / self.factor, off / self.factor, volume)
+    }
+}
+
+pub struct Scale {
+    inner: Box<dyn Envelope>,
+    level: f64,
+}
+
+impl Scale {
+    pub fn new(inner: Box<dyn Envelope>, level: f64) -> Self {
+        Self { inner, level }
+    }
+}
+
+impl Envelope for Scale {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        self.level * self.inner.value_at(t, volume)
+    }
+
+    fn min(&self) -> f64 {
+        self.inner.min()
+    }
+
+    fn release(&self) -> f64 {
+        self.inner.release()
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        self.level * self.inner.released_at(t, off, volume)
+    }
+}
+
+// Flips the envelope within 0.0 - volume, e.g. turning a swell into a dip
+pub struct Invert {
+    inner: Box<dyn Envelope>,
+}
+
+impl Invert {
+    pub fn new(inner: Box<dyn Envelope>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Envelope for Invert {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        volume - self.inner.value_at(t, volume)
+    }
+
+    fn min(&self) -> f64 {
+        self.inner.min()
+    }
+
+    fn release(&self) -> f64 {
+        self.inner.release()
+    }
+
+    // The inverted release would swell back up to full volume, so it gets
+    // faded out over the release time
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        let inverted = volume - self.inner.released_at(t, off, volume);
+        if t < off {
+            return inverted;
+        }
+        let release = self.release();
+        if t >= off + release {
+            return 0.0;
+        }
+        inverted * (1.0 - (t - off) / release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{Fixed, ADSR, ASR, RAR};
+    use crate::testing::assert_close;
+
+    #[test]
+    fn product_and_sum() {
+        let product = Product::new(Box::new(RAR::new(0.1, 0.1)), Box::new(RAR::new(0.2, 0.0)));
+        assert_close(0.125, product.value_at(0.05, 1.0));
+        assert_close(0.2, product.min());
+
+        let sum = Sum::new(Box::new(RAR::new(0.1, 0.1)), Box::new(Fixed {}));
+        assert_close(1.5, sum.value_at(0.05, 1.0));
+    }
+
+    #[test]
+    fn sequence_follows_on() {
+        let env = Sequence::new(
+            Box::new(ASR::new(0.1, 0.0, 0.1)),
+            Box::new(ASR::new(0.2, 0.0, 0.2)),
+        );
+        assert_close(0.5, env.value_at(0.05, 1.0));
+        assert_close(0.5, env.value_at(0.15, 1.0));
+        assert_close(0.5, env.value_at(0.3, 1.0));
+        assert_close(0.6, env.min());
+    }
+
+    #[test]
+    fn stretch_scale_and_invert() {
+        let stretched = Stretch::new(Box::new(ASR::new(0.1, 0.0, 0.1)), 2.0);
+        assert_close(0.5, stretched.value_at(0.1, 1.0));
+        assert_close(0.4, stretched.min());
+        assert_close(0.2, stretched.release());
+
+        let scaled = Scale::new(Box::new(RAR::new(0.1, 0.1)), 0.5);
+        assert_close(0.25, scaled.value_at(0.05, 1.0));
+
+        let inverted = Invert::new(Box::new(RAR::new(0.1, 0.1)));
+        assert_close(0.75, inverted.value_at(0.025, 1.0));
+    }
+
+    #[test]
+    fn inverted_release_ends_silent() {
+        let inverted = Invert::new(Box::new(ADSR::new(0.1, 0.1, 0.5, 0.4)));
+        assert_close(0.5, inverted.released_at(0.3, 0.3, 1.0));
+        // Inner release is halfway down to 0.25, inverted 0.75, half faded
+        assert_close(0.375, inverted.released_at(0.5, 0.3, 1.0));
+        assert_close(0.0, inverted.released_at(0.7, 0.3, 1.0));
+        let fade: Vec<f64> = (0..400)
+            .map(|i| inverted.released_at(0.3 + i as f64 / 1000.0, 0.3, 1.0))
+            .collect();
+        assert!(fade.windows(2).all(|w| (w[1] - w[0]).abs() < 0.01));
+        assert!(fade[399] < 0.01);
+    }
+
+    #[test]
+    fn stretch_keeps_time_moving() {
+        for factor in [0.0, -2.0] {
+            let stretched = Stretch::new(Box::new(RAR::new(0.1, 0.1)), factor);
+            assert!(stretched.min() > 0.0);
+            let value = stretched.value_at(0.05, 1.0);
+            assert!(value.is_finite() && value >= 0.0);
+        }
+    }
+
+    #[test]
+    fn combinators_keep_note_off() {
+        let adsr = || Box::new(ADSR::new(0.0, 0.0, 1.0, 0.4));
+        let env = Scale::new(Box::new(Stretch::new(adsr(), 0.5)), 0.5);
+        assert_close(0.2, env.release());
+        assert_close(0.25, env.released_at(1.1, 1.0, 1.0));
+    }
+}
@@ -0,0 +1,74 @@
+// Shape of a single envelope stage. Tension controls how pronounced the
+// bend is; zero or less falls back to a straight line.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Curve {
+    #[default]
+    Linear,
+    Exponential(f64),
+    Logarithmic(f64),
+    S(f64),
+}
+
+impl Curve {
+    // Maps linear progress through a stage (0.0 - 1.0) onto the curve
+    pub fn apply(&self, x: f64) -> f64 {
+        let x = x.clamp(0.0, 1.0);
+        match *self {
+            Self::Exponential(tension) if tension > 0.0 => {
+                ((tension * x).exp() - 1.0) / (tension.exp() - 1.0)
+            }
+            Self::Logarithmic(tension) if tension > 0.0 => {
+                1.0 - Self::Exponential(tension).apply(1.0 - x)
+            }
+            Self::S(tension) if tension > 0.0 => {
+                let rise = x.powf(tension);
+                rise / (rise + (1.0 - x).powf(tension))
+            }
+            _ => x,
+        }
+    }
+
+    // Level of a rising stage
+    pub fn rise(&self, x: f64) -> f64 {
+        self.apply(x)
+    }
+
+    // Level of a falling stage
+    pub fn fall(&self, x: f64) -> f64 {
+        1.0 - self.apply(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_span_whole_stage() {
+        for curve in [
+            Curve::Linear,
+            Curve::Exponential(4.0),
+            Curve::Logarithmic(4.0),
+            Curve::S(3.0),
+        ] {
+            assert!(curve.apply(0.0).abs() < 1e-12, "{:?}", curve);
+            assert!((curve.apply(1.0) - 1.0).abs() < 1e-12, "{:?}", curve);
+            let mut last = 0.0;
+            for i in 1..=100 {
+                let y = curve.apply(i as f64 / 100.0);
+                assert!(y >= last, "{:?} is not monotonic", curve);
+                last = y;
+            }
+        }
+    }
+
+    #[test]
+    fn curves_bend() {
+        assert!(Curve::Exponential(4.0).apply(0.5) < 0.5);
+        assert!(Curve::Logarithmic(4.0).apply(0.5) > 0.5);
+        assert!((Curve::S(3.0).apply(0.5) - 0.5).abs() < 1e-12);
+        assert!(Curve::S(3.0).apply(0.25) < 0.25);
+        assert!(Curve::S(3.0).apply(0.75) > 0.75);
+        assert_eq!(0.3, Curve::Exponential(0.0).apply(0.3));
+    }
+}
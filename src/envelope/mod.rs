@@ -1,9 +1,36 @@
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
+pub mod curve;
+use curve::Curve;
+
+mod breakpoint;
+pub use breakpoint::Breakpoint;
+
+mod combine;
+pub use combine::{Invert, Product, Scale, Sequence, Stretch, Sum};
+
 pub trait Envelope {
     fn value_at(&self, t: f64, volume: f64) -> f64;
     fn min(&self) -> f64;
+
+    // How long the sound keeps going after note-off
+    fn release(&self) -> f64 {
+        0.0
+    }
+
+    // Value with the note released at `off`: by default it fades out
+    // linearly from wherever the envelope was at the time.
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        if t < off {
+            return self.value_at(t, volume);
+        }
+        let release = self.release();
+        if t >= off + release {
+            return 0.0;
+        }
+        self.value_at(off, volume) * (1.0 - (t - off) / release)
+    }
 }
 
 pub trait Delayed {
@@ -31,6 +58,19 @@ where
     fn min(&self) -> f64 {
         self.get_delay() + self.get_inner().min()
     }
+
+    fn release(&self) -> f64 {
+        self.get_inner().release()
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        let delay = self.get_delay();
+        if t < delay {
+            0.0
+        } else {
+            self.get_inner().released_at(t - delay, off - delay, volume)
+        }
+    }
 }
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -51,6 +91,7 @@ pub struct RAR {
     attack: f64,
     release: f64,
     duration: f64,
+    curves: (Curve, Curve),
 }
 
 impl RAR {
@@ -60,8 +101,14 @@ impl RAR {
             attack,
             release,
             duration,
+            curves: Default::default(),
         }
     }
+
+    pub fn with_curves(mut self, attack: Curve, release: Curve) -> Self {
+        self.curves = (attack, release);
+        self
+    }
 }
 
 impl Relative for RAR {
@@ -74,13 +121,13 @@ impl Relative for RAR {
 impl Envelope for RAR {
     fn value_at(&self, t: f64, volume: f64) -> f64 {
         if t < self.attack {
-            return volume * (t / self.attack);
+            return volume * self.curves.0.rise(t / self.attack);
         }
 
         let minr = self.duration - self.release;
         if t > minr {
             let posr = self.duration - t;
-            return volume * (posr / self.release);
+            return volume * self.curves.1.fall(1.0 - posr / self.release);
         }
 
         volume
@@ -89,6 +136,23 @@ impl Envelope for RAR {
     fn min(&self) -> f64 {
         self.duration.max(self.attack + self.release)
     }
+
+    fn release(&self) -> f64 {
+        self.release
+    }
+
+    // Released before its own release has started, it falls from wherever
+    // it was along the release curve
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        if t < off || off >= self.duration - self.release {
+            return self.value_at(t, volume);
+        }
+        if t >= off + self.release {
+            return 0.0;
+        }
+        let posr = (t - off) / self.release;
+        self.value_at(off, volume) * self.curves.1.fall(posr)
+    }
 }
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -102,6 +166,11 @@ impl DRAR {
         let inner = RAR::new(attack, release);
         Self { delay, inner }
     }
+
+    pub fn with_curves(mut self, attack: Curve, release: Curve) -> Self {
+        self.inner = self.inner.with_curves(attack, release);
+        self
+    }
 }
 
 impl Delayed for DRAR {
@@ -119,6 +188,7 @@ pub struct ASR {
     attack: f64,
     sustain: f64,
     release: f64,
+    curves: (Curve, Curve),
 }
 
 impl ASR {
@@ -127,13 +197,19 @@ impl ASR {
             attack,
             sustain,
             release,
+            curves: Default::default(),
         }
     }
+
+    pub fn with_curves(mut self, attack: Curve, release: Curve) -> Self {
+        self.curves = (attack, release);
+        self
+    }
 }
 impl Envelope for ASR {
     fn value_at(&self, t: f64, volume: f64) -> f64 {
         if t < self.attack {
-            return volume * (t / self.attack);
+            return volume * self.curves.0.rise(t / self.attack);
         }
 
         if t > self.attack && t < self.attack + self.sustain {
@@ -143,7 +219,7 @@ impl Envelope for ASR {
         let duration = self.min();
         if t >= self.sustain && t < duration {
             let posr = duration - t;
-            return volume * (posr / self.release);
+            return volume * self.curves.1.fall(1.0 - posr / self.release);
         }
 
         0.0
@@ -152,6 +228,23 @@ impl Envelope for ASR {
     fn min(&self) -> f64 {
         self.attack + self.sustain + self.release
     }
+
+    fn release(&self) -> f64 {
+        self.release
+    }
+
+    // Released before the sustain is over, it falls from wherever it was
+    // along the release curve
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        if t < off || off >= self.attack + self.sustain {
+            return self.value_at(t, volume);
+        }
+        if t >= off + self.release {
+            return 0.0;
+        }
+        let posr = (t - off) / self.release;
+        self.value_at(off, volume) * self.curves.1.fall(posr)
+    }
 }
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -164,6 +257,11 @@ impl DASR {
         let inner = ASR::new(attack, sustain, release);
         Self { delay, inner }
     }
+
+    pub fn with_curves(mut self, attack: Curve, release: Curve) -> Self {
+        self.inner = self.inner.with_curves(attack, release);
+        self
+    }
 }
 impl Delayed for DASR {
     fn get_delay(&self) -> f64 {
@@ -173,3 +271,204 @@ impl Delayed for DASR {
         &self.inner
     }
 }
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug)]
+pub struct ADSR {
+    attack: f64,
+    decay: f64,
+    sustain: f64,
+    release: f64,
+    duration: f64,
+    curves: (Curve, Curve, Curve),
+}
+
+impl ADSR {
+    pub fn new(attack: f64, decay: f64, sustain: f64, release: f64) -> Self {
+        Self {
+            attack,
+            decay,
+            sustain: sustain.clamp(0.0, 1.0),
+            release,
+            duration: attack + decay,
+            curves: Default::default(),
+        }
+    }
+
+    pub fn with_curves(mut self, attack: Curve, decay: Curve, release: Curve) -> Self {
+        self.curves = (attack, decay, release);
+        self
+    }
+
+    fn level_at(&self, t: f64) -> f64 {
+        if t < self.attack {
+            return self.curves.0.rise(t / self.attack);
+        }
+        if t < self.attack + self.decay {
+            let posd = (t - self.attack) / self.decay;
+            return self.sustain + (1.0 - self.sustain) * self.curves.1.fall(posd);
+        }
+        self.sustain
+    }
+}
+
+impl Relative for ADSR {
+    fn set_duration(&mut self, d: f64) -> &mut Self {
+        self.duration = d;
+        self
+    }
+}
+
+impl Envelope for ADSR {
+    fn value_at(&self, t: f64, volume: f64) -> f64 {
+        self.released_at(t, self.duration, volume)
+    }
+
+    fn min(&self) -> f64 {
+        self.duration.max(self.attack + self.decay) + self.release
+    }
+
+    fn release(&self) -> f64 {
+        self.release
+    }
+
+    fn released_at(&self, t: f64, off: f64, volume: f64) -> f64 {
+        if t < off {
+            return volume * self.level_at(t);
+        }
+        if t >= off + self.release {
+            return 0.0;
+        }
+        let posr = (t - off) / self.release;
+        volume * self.level_at(off) * self.curves.2.fall(posr)
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct DADSR {
+    delay: f64,
+    inner: ADSR,
+}
+
+impl DADSR {
+    pub fn new(delay: f64, attack: f64, decay: f64, sustain: f64, release: f64) -> Self {
+        let inner = ADSR::new(attack, decay, sustain, release);
+        Self { delay, inner }
+    }
+
+    pub fn with_curves(mut self, attack: Curve, decay: Curve, release: Curve) -> Self {
+        self.inner = self.inner.with_curves(attack, decay, release);
+        self
+    }
+}
+
+impl Relative for DADSR {
+    fn set_duration(&mut self, d: f64) -> &mut Self {
+        self.inner.set_duration((d - self.delay).max(0.0));
+        self
+    }
+}
+
+impl Delayed for DADSR {
+    fn get_delay(&self) -> f64 {
+        self.delay
+    }
+    fn get_inner(&self) -> &dyn Envelope {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::assert_close;
+
+    #[test]
+    fn adsr_stages() {
+        let mut env = ADSR::new(0.1, 0.2, 0.5, 0.4);
+        env.set_duration(1.0);
+
+        assert_close(0.0, env.value_at(0.0, 1.0));
+        assert_close(0.5, env.value_at(0.05, 1.0));
+        assert_close(1.0, env.value_at(0.1, 1.0));
+        assert_close(0.75, env.value_at(0.2, 1.0));
+        assert_close(0.5, env.value_at(0.3, 1.0));
+        assert_close(0.25, env.value_at(0.9, 0.5));
+        assert_close(0.25, env.value_at(1.2, 1.0));
+        assert_close(0.0, env.value_at(1.4, 1.0));
+        assert_close(1.4, env.min());
+    }
+
+    #[test]
+    fn adsr_releases_from_current_level() {
+        let env = ADSR::new(0.1, 0.2, 0.5, 0.4);
+
+        assert_close(0.5, env.released_at(0.05, 0.05, 1.0));
+        assert_close(0.25, env.released_at(0.25, 0.05, 1.0));
+        assert_close(0.0, env.released_at(0.45, 0.05, 1.0));
+    }
+
+    #[test]
+    fn curves_shape_stages() {
+        let linear = RAR::new(0.1, 0.1);
+        let curved =
+            RAR::new(0.1, 0.1).with_curves(Curve::Exponential(4.0), Curve::Logarithmic(4.0));
+        assert!(curved.value_at(0.05, 1.0) < linear.value_at(0.05, 1.0));
+        assert!(curved.value_at(0.15, 1.0) < linear.value_at(0.15, 1.0));
+
+        let adsr = ADSR::new(0.1, 0.2, 0.5, 0.4).with_curves(
+            Curve::Linear,
+            Curve::Logarithmic(4.0),
+            Curve::S(3.0),
+        );
+        assert_close(1.0, adsr.value_at(0.1, 1.0));
+        assert!(adsr.value_at(0.2, 1.0) < 0.75);
+        assert_close(0.5, adsr.value_at(0.3, 1.0));
+        assert_close(0.25, adsr.released_at(0.5, 0.3, 1.0));
+        assert!(adsr.released_at(0.4, 0.3, 1.0) > 0.375);
+    }
+
+    #[test]
+    fn asr_releases_along_curve() {
+        let curve = Curve::Exponential(4.0);
+        let env = ASR::new(0.1, 1.0, 0.4).with_curves(Curve::Linear, curve);
+
+        assert_close(0.5, env.released_at(0.2, 0.3, 0.5));
+        for posr in [0.0, 0.25, 0.5, 0.75] {
+            let t = 0.3 + posr * 0.4;
+            assert_close(0.5 * curve.fall(posr), env.released_at(t, 0.3, 0.5));
+        }
+        // Where a straight fade would be halfway down already
+        assert!(env.released_at(0.5, 0.3, 1.0) > 0.8);
+        assert_close(0.0, env.released_at(0.7, 0.3, 1.0));
+        // Once the sustain is over the envelope runs its own course
+        assert_close(env.value_at(1.3, 1.0), env.released_at(1.3, 1.2, 1.0));
+    }
+
+    #[test]
+    fn rar_releases_along_curve() {
+        let curve = Curve::Exponential(4.0);
+        let mut env = RAR::new(0.1, 0.4).with_curves(Curve::Linear, curve);
+        env.set_duration(1.5);
+
+        for posr in [0.0, 0.25, 0.5, 0.75] {
+            let t = 0.3 + posr * 0.4;
+            assert_close(0.5 * curve.fall(posr), env.released_at(t, 0.3, 0.5));
+        }
+        assert!(env.released_at(0.5, 0.3, 1.0) > 0.8);
+        assert_close(0.0, env.released_at(0.7, 0.3, 1.0));
+        assert_close(env.value_at(1.3, 1.0), env.released_at(1.3, 1.2, 1.0));
+    }
+
+    #[test]
+    fn dadsr_waits_for_delay() {
+        let mut env = DADSR::new(0.5, 0.1, 0.2, 0.5, 0.4);
+        env.set_duration(1.5);
+
+        assert_close(0.0, env.value_at(0.4, 1.0));
+        assert_close(1.0, env.value_at(0.6, 1.0));
+        assert_close(0.5, env.value_at(1.2, 1.0));
+        assert_close(0.25, env.released_at(1.7, 1.5, 1.0));
+        assert_close(0.4, env.release());
+    }
+}
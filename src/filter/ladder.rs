@@ -0,0 +1,47 @@
+use super::{Mode, State};
+use std::f64::consts::PI;
+
+// Feedback amount at full resonance. Past 4.0 the loop gain exceeds unity
+// and the filter rings on its own, with the input tanh keeping it bounded.
+const MAX_FEEDBACK: f64 = 4.5;
+
+// Moog-style ladder: four trapezoidal one-pole stages with the feedback loop
+// solved without a unit delay, so resonance doesn't drift with cutoff. The
+// input is driven into tanh saturation. Low-pass is the last stage, the
+// other modes are mixes of the intermediate ones.
+pub(super) fn tick(
+    state: &mut State,
+    mode: Mode,
+    input: f64,
+    cutoff: f64,
+    resonance: f64,
+    drive: f64,
+    sample_rate: i32,
+) -> f64 {
+    let g = (PI * cutoff / sample_rate as f64).tan();
+    let gain = g / (1.0 + g);
+    let k = MAX_FEEDBACK * resonance;
+
+    let stages = [&mut state.s1, &mut state.s2, &mut state.s3, &mut state.s4];
+    let carry = stages
+        .iter()
+        .fold(0.0, |sum, s| sum * gain + **s / (1.0 + g));
+    let u = ((drive * input - k * carry) / (1.0 + k * gain.powi(4))).tanh();
+
+    let mut outputs = [0.0; 4];
+    let mut x = u;
+    for (s, y) in stages.into_iter().zip(outputs.iter_mut()) {
+        let v = (x - *s) * gain;
+        *y = v + *s;
+        *s = *y + v;
+        x = *y;
+    }
+
+    let [a, b, c, d] = outputs;
+    match mode {
+        Mode::LowPass => d,
+        Mode::HighPass => u - 4.0 * a + 6.0 * b - 4.0 * c + d,
+        Mode::BandPass => 4.0 * (b - 2.0 * c + d),
+        Mode::Notch => u - 4.0 * a + 6.0 * b - 4.0 * c + 2.0 * d,
+    }
+}
@@ -0,0 +1,324 @@
+use crate::envelope::Envelope;
+use crate::modulation::Context;
+use std::f64::consts::PI;
+
+mod ladder;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Topology {
+    StateVariable,
+    Biquad,
+    Ladder,
+}
+
+// Running state of a filter, one per voice
+#[derive(Copy, Clone, Debug, Default)]
+pub struct State {
+    s1: f64,
+    s2: f64,
+    s3: f64,
+    s4: f64,
+}
+
+// Resonant filter sitting between generator and envelope. Resonance goes
+// from 0.0 (none) to 1.0 (just short of self-oscillation). Cutoff can follow
+// its own envelope and the note pitch, with both amounts given in octaves.
+// The ladder is the exception: it self-oscillates towards full resonance,
+// and saturates its input according to drive.
+pub struct Filter {
+    mode: Mode,
+    topology: Topology,
+    cutoff: f64,
+    resonance: f64,
+    envelope: Option<(Box<dyn Envelope>, f64)>,
+    tracking: f64,
+    drive: f64,
+}
+
+impl Filter {
+    pub fn new(topology: Topology, mode: Mode, cutoff: f64, resonance: f64) -> Self {
+        Self {
+            mode,
+            topology,
+            cutoff,
+            resonance: resonance.clamp(0.0, 1.0),
+            envelope: None,
+            tracking: 0.0,
+            drive: 1.0,
+        }
+    }
+
+    pub fn svf(mode: Mode, cutoff: f64, resonance: f64) -> Self {
+        Self::new(Topology::StateVariable, mode, cutoff, resonance)
+    }
+
+    pub fn biquad(mode: Mode, cutoff: f64, resonance: f64) -> Self {
+        Self::new(Topology::Biquad, mode, cutoff, resonance)
+    }
+
+    // 24dB/oct low-pass
+    pub fn ladder(cutoff: f64, resonance: f64) -> Self {
+        Self::new(Topology::Ladder, Mode::LowPass, cutoff, resonance)
+    }
+
+    pub fn low_pass(cutoff: f64, resonance: f64) -> Self {
+        Self::svf(Mode::LowPass, cutoff, resonance)
+    }
+
+    pub fn high_pass(cutoff: f64, resonance: f64) -> Self {
+        Self::svf(Mode::HighPass, cutoff, resonance)
+    }
+
+    pub fn with_env_box(mut self, e: Box<dyn Envelope>, octaves: f64) -> Self {
+        self.envelope = Some((e, octaves));
+        self
+    }
+
+    pub fn with_envelope(self, e: impl Envelope + 'static, octaves: f64) -> Self {
+        self.with_env_box(Box::new(e), octaves)
+    }
+
+    // 1.0 moves the cutoff an octave for every octave played above A4
+    pub fn with_tracking(mut self, amount: f64) -> Self {
+        self.tracking = amount;
+        self
+    }
+
+    // Input gain into the ladder's saturation, 1.0 being clean-ish
+    pub fn with_drive(mut self, drive: f64) -> Self {
+        self.drive = drive.max(0.0);
+        self
+    }
+
+    // Cutoff in Hz at a point in the note, shifted by `octaves` on top of
+    // the filter's own envelope and key tracking.
+    pub fn cutoff_at(&self, ctx: &Context, octaves: f64) -> f64 {
+        let mut octaves = octaves;
+        if let Some((envelope, amount)) = &self.envelope {
+            octaves += amount * envelope.released_at(ctx.t, ctx.off, 1.0);
+        }
+        if self.tracking != 0.0 && ctx.frequency > 0.0 {
+            octaves += self.tracking * (ctx.frequency / 440.0).log2();
+        }
+        self.cutoff * 2.0_f64.powf(octaves)
+    }
+
+    pub fn process(&self, state: &mut State, input: f64, cutoff: f64, sample_rate: i32) -> f64 {
+        let cutoff = cutoff.clamp(10.0, 0.49 * sample_rate as f64);
+        // Damping, 2.0 being no resonance at all
+        let k = 2.0 * (1.0 - 0.995 * self.resonance);
+        match self.topology {
+            Topology::StateVariable => self.svf_tick(state, input, cutoff, k, sample_rate),
+            Topology::Biquad => self.biquad_tick(state, input, cutoff, k, sample_rate),
+            Topology::Ladder => ladder::tick(
+                state,
+                self.mode,
+                input,
+                cutoff,
+                self.resonance,
+                self.drive,
+                sample_rate,
+            ),
+        }
+    }
+
+    // Filters the whole buffer with a fixed cutoff
+    pub fn run(&self, buffer: &mut [f64], sample_rate: i32) {
+        let mut state = State::default();
+        for x in buffer.iter_mut() {
+            *x = self.process(&mut state, *x, self.cutoff, sample_rate);
+        }
+    }
+
+    // Trapezoidal state variable filter (Simper)
+    fn svf_tick(
+        &self,
+        state: &mut State,
+        input: f64,
+        cutoff: f64,
+        k: f64,
+        sample_rate: i32,
+    ) -> f64 {
+        let g = (PI * cutoff / sample_rate as f64).tan();
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - state.s2;
+        let band = a1 * state.s1 + a2 * v3;
+        let low = state.s2 + a2 * state.s1 + a3 * v3;
+        state.s1 = 2.0 * band - state.s1;
+        state.s2 = 2.0 * low - state.s2;
+
+        let high = input - k * band - low;
+        match self.mode {
+            Mode::LowPass => low,
+            Mode::HighPass => high,
+            Mode::BandPass => band,
+            Mode::Notch => low + high,
+        }
+    }
+
+    // RBJ cookbook biquad, transposed direct form II
+    fn biquad_tick(
+        &self,
+        state: &mut State,
+        input: f64,
+        cutoff: f64,
+        k: f64,
+        sample_rate: i32,
+    ) -> f64 {
+        let w0 = 2.0 * PI * cutoff / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin * k / 2.0;
+
+        let (b0, b1, b2) = match self.mode {
+            Mode::LowPass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+            Mode::HighPass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+            Mode::BandPass => (alpha, 0.0, -alpha),
+            Mode::Notch => (1.0, -2.0 * cos, 1.0),
+        };
+        let a0 = 1.0 + alpha;
+        let (a1, a2) = (-2.0 * cos, 1.0 - alpha);
+
+        let output = (b0 * input + state.s1) / a0;
+        state.s1 = b1 * input - a1 * output + state.s2;
+        state.s2 = b2 * input - a2 * output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: i32 = 44100;
+
+    // Steady-state gain for a sine at the given frequency
+    fn gain(filter: &Filter, frequency: f64) -> f64 {
+        let mut sound: Vec<f64> = (0..RATE as usize)
+            .map(|i| (2.0 * PI * frequency * i as f64 / RATE as f64).sin())
+            .collect();
+        filter.run(&mut sound, RATE);
+        sound[RATE as usize / 2..]
+            .iter()
+            .fold(0.0, |x: f64, y| x.max(y.abs()))
+    }
+
+    #[test]
+    fn modes_shape_response() {
+        for topology in [Topology::StateVariable, Topology::Biquad] {
+            let lp = Filter::new(topology, Mode::LowPass, 1000.0, 0.0);
+            assert!(gain(&lp, 100.0) > 0.9, "{:?}", topology);
+            assert!(gain(&lp, 10000.0) < 0.05, "{:?}", topology);
+
+            let hp = Filter::new(topology, Mode::HighPass, 1000.0, 0.0);
+            assert!(gain(&hp, 100.0) < 0.05, "{:?}", topology);
+            assert!(gain(&hp, 10000.0) > 0.9, "{:?}", topology);
+
+            let bp = Filter::new(topology, Mode::BandPass, 1000.0, 0.5);
+            assert!(gain(&bp, 1000.0) > 0.9, "{:?}", topology);
+            assert!(gain(&bp, 100.0) < 0.2, "{:?}", topology);
+            assert!(gain(&bp, 10000.0) < 0.2, "{:?}", topology);
+
+            let notch = Filter::new(topology, Mode::Notch, 1000.0, 0.0);
+            assert!(gain(&notch, 1000.0) < 0.05, "{:?}", topology);
+            assert!(gain(&notch, 100.0) > 0.9, "{:?}", topology);
+        }
+    }
+
+    #[test]
+    fn resonance_boosts_cutoff() {
+        for topology in [Topology::StateVariable, Topology::Biquad] {
+            let flat = Filter::new(topology, Mode::LowPass, 1000.0, 0.0);
+            let peaky = Filter::new(topology, Mode::LowPass, 1000.0, 0.9);
+            assert!(gain(&peaky, 1000.0) > 2.0 * gain(&flat, 1000.0));
+        }
+    }
+
+    #[test]
+    fn cutoff_follows_envelope_and_tracking() {
+        use crate::envelope::ASR;
+
+        let filter = Filter::low_pass(1000.0, 0.0)
+            .with_envelope(ASR::new(1.0, 0.0, 0.0), 2.0)
+            .with_tracking(1.0);
+        let ctx = |t, frequency| Context {
+            t,
+            off: f64::INFINITY,
+            frequency,
+            velocity: 1.0,
+            sample_rate: RATE,
+        };
+        assert!((filter.cutoff_at(&ctx(0.0, 440.0), 0.0) - 1000.0).abs() < 1e-9);
+        assert!((filter.cutoff_at(&ctx(0.5, 440.0), 0.0) - 2000.0).abs() < 1e-9);
+        assert!((filter.cutoff_at(&ctx(0.0, 880.0), 0.0) - 2000.0).abs() < 1e-9);
+        assert!((filter.cutoff_at(&ctx(0.0, 440.0), -1.0) - 500.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn instrument_filters_before_envelope() {
+        use crate::envelope::Fixed;
+        use crate::generator::simple::Simple;
+        use crate::lfo::LFO;
+        use crate::modulation::{Destination, Source};
+        use crate::Instrument;
+        use note::*;
+
+        let energy = |x: &[f64]| x.iter().map(|y| y * y).sum::<f64>();
+        let note = note![A: C4, 1 / 4];
+        let raw = Instrument::new(Simple::square(), Fixed {});
+        let dark =
+            Instrument::new(Simple::square(), Fixed {}).with_filter(Filter::low_pass(300.0, 0.0));
+        let wobble = Instrument::new(Simple::square(), Fixed {})
+            .with_filter(Filter::low_pass(300.0, 0.0))
+            .with_modulation(Source::lfo(LFO::sine(2.0)), Destination::Cutoff, 3.0);
+
+        let raw = raw.play(120.0, note, 1.0);
+        let dark = dark.play(120.0, note, 1.0);
+        let wobble = wobble.play(120.0, note, 1.0);
+        assert_eq!(raw.len(), dark.len());
+        assert!(energy(&dark) < energy(&raw) / 4.0);
+        assert!(energy(&wobble) > energy(&dark));
+    }
+
+    #[test]
+    fn ladder_rolls_off_steeper() {
+        let ladder = Filter::ladder(1000.0, 0.0);
+        let svf = Filter::low_pass(1000.0, 0.0);
+        assert!(gain(&ladder, 100.0) > 0.7);
+        assert!(gain(&ladder, 8000.0) < 0.01);
+        assert!(gain(&ladder, 8000.0) < gain(&svf, 8000.0) / 4.0);
+    }
+
+    #[test]
+    fn ladder_self_oscillates() {
+        let mut sound = vec![0.0; RATE as usize];
+        sound[0] = 1.0;
+        Filter::ladder(1000.0, 1.0).run(&mut sound, RATE);
+        let peak = |x: &[f64]| x.iter().fold(0.0, |x: f64, y| x.max(y.abs()));
+        assert!(peak(&sound[RATE as usize - 1000..]) > 0.1);
+
+        let mut sound = vec![0.0; RATE as usize];
+        sound[0] = 1.0;
+        Filter::ladder(1000.0, 0.5).run(&mut sound, RATE);
+        assert!(peak(&sound[RATE as usize - 1000..]) < 1e-6);
+    }
+
+    #[test]
+    fn ladder_drive_saturates() {
+        let clean = Filter::ladder(5000.0, 0.0);
+        let driven = Filter::ladder(5000.0, 0.0).with_drive(10.0);
+        assert!(gain(&clean, 100.0) < 1.0);
+        assert!(gain(&driven, 100.0) > gain(&clean, 100.0));
+        assert!(gain(&driven, 100.0) < 1.2);
+    }
+}
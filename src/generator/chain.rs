@@ -1,42 +1,114 @@
 use super::*;
-use crate::oscillator::Oscillator;
+use crate::oscillator::{Oscillator, Phasor};
 
 enum Operator {
     Add(Box<dyn Signal>),
     Sub(Box<dyn Signal>),
+    Mul(Box<dyn Signal>),
+    Div(Box<dyn Signal>),
+    Min(Box<dyn Signal>),
+    Max(Box<dyn Signal>),
+    Crossfade(Box<dyn Signal>, f64),
+}
+
+impl Operator {
+    fn signal(&self) -> &dyn Signal {
+        match self {
+            Self::Add(x)
+            | Self::Sub(x)
+            | Self::Mul(x)
+            | Self::Div(x)
+            | Self::Min(x)
+            | Self::Max(x)
+            | Self::Crossfade(x, _) => x.as_ref(),
+        }
+    }
+
+    fn apply(&self, val: f64, by: f64) -> f64 {
+        match self {
+            Self::Add(_) => val + by,
+            Self::Sub(_) => val - by,
+            Self::Mul(_) => val * by,
+            Self::Div(_) => val * soft_recip(by),
+            Self::Min(_) => val.min(by),
+            Self::Max(_) => val.max(by),
+            Self::Crossfade(_, amount) => val * (1.0 - amount) + by * amount,
+        }
+    }
+}
+
+// Divisors closer to zero than this get softened
+const DIV_SOFTNESS: f64 = 0.1;
+
+// Exact reciprocal for divisors at least `DIV_SOFTNESS` away from zero.
+// Closer in it follows a cubic that meets 1/x with the same slope at the
+// edge and passes through zero instead of blowing up, topping out at about
+// 11x the value.
+fn soft_recip(by: f64) -> f64 {
+    if by.abs() >= DIV_SOFTNESS {
+        return 1.0 / by;
+    }
+    let x = by / DIV_SOFTNESS;
+    x * (2.0 - x * x) / DIV_SOFTNESS
 }
 
 pub struct Chain {
-    base: Oscillator,
+    base: Box<dyn Signal>,
     mods: Vec<Operator>,
 }
 
 impl Signal for Chain {
-    fn value_at(&self, t: f64, frequency: f64) -> f64 {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.value_with(t, frequency, sample_rate, &Params::default())
+    }
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> f64 {
+        let value = |x: &dyn Signal| x.value_with(t, frequency, sample_rate, params);
+        self.mods.iter().fold(value(self.base.as_ref()), |val, x| {
+            x.apply(val, value(x.signal()))
+        })
+    }
+
+    // Operators apply to each channel on its own
+    fn stereo_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> (f64, f64) {
+        let value = |x: &dyn Signal| x.stereo_with(t, frequency, sample_rate, params);
         self.mods
             .iter()
-            .fold(self.base.get(frequency).at(t), |val, x| match x {
-                Operator::Add(x) => val + x.value_at(t, frequency),
-                Operator::Sub(x) => val - x.value_at(t, frequency),
+            .fold(value(self.base.as_ref()), |(left, right), x| {
+                let by = value(x.signal());
+                (x.apply(left, by.0), x.apply(right, by.1))
             })
     }
+
+    fn save(&self, state: &mut Vec<State>) {
+        self.base.save(state);
+        self.mods.iter().for_each(|x| x.signal().save(state));
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        self.base.load(state);
+        self.mods.iter().for_each(|x| x.signal().load(state));
+    }
 }
 
 impl Synth for Chain {}
 
 impl Default for Chain {
     fn default() -> Self {
-        Self {
-            base: Oscillator::Sine,
-            mods: Vec::new(),
-        }
+        Self::new(Oscillator::Sine)
     }
 }
 
 impl Chain {
     pub fn new(base: Oscillator) -> Self {
+        Self::with_base(base)
+    }
+    pub fn phased(base: Oscillator) -> Self {
+        Self::with_base(Phasor::new(base))
+    }
+    pub fn with_base(base: impl Signal + 'static) -> Self {
         Self {
-            base,
+            base: Box::new(base),
             mods: Vec::new(),
         }
     }
@@ -54,4 +126,104 @@ impl Chain {
         self.mods.push(Operator::Sub(what));
         self
     }
+    pub fn mul(&mut self, what: impl Signal + 'static) -> &mut Self {
+        self.mul_box(Box::new(what))
+    }
+    pub fn mul_box(&mut self, what: Box<dyn Signal>) -> &mut Self {
+        self.mods.push(Operator::Mul(what));
+        self
+    }
+    pub fn div(&mut self, what: impl Signal + 'static) -> &mut Self {
+        self.div_box(Box::new(what))
+    }
+    pub fn div_box(&mut self, what: Box<dyn Signal>) -> &mut Self {
+        self.mods.push(Operator::Div(what));
+        self
+    }
+    pub fn min(&mut self, what: impl Signal + 'static) -> &mut Self {
+        self.min_box(Box::new(what))
+    }
+    pub fn min_box(&mut self, what: Box<dyn Signal>) -> &mut Self {
+        self.mods.push(Operator::Min(what));
+        self
+    }
+    pub fn max(&mut self, what: impl Signal + 'static) -> &mut Self {
+        self.max_box(Box::new(what))
+    }
+    pub fn max_box(&mut self, what: Box<dyn Signal>) -> &mut Self {
+        self.mods.push(Operator::Max(what));
+        self
+    }
+    pub fn crossfade(&mut self, what: impl Signal + 'static, amount: f64) -> &mut Self {
+        self.crossfade_box(Box::new(what), amount)
+    }
+    pub fn crossfade_box(&mut self, what: Box<dyn Signal>, amount: f64) -> &mut Self {
+        self.mods
+            .push(Operator::Crossfade(what, amount.clamp(0.0, 1.0)));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lfo::LFO;
+    use crate::testing::Constant;
+    use std::f64::consts::PI;
+
+    fn value(chain: &Chain) -> f64 {
+        chain.value_at(0.25 / 440.0, 440.0, 44100)
+    }
+
+    #[test]
+    fn ring_modulation() {
+        let mut chain = Chain::new(Oscillator::Sine);
+        chain.mul(LFO::sine(30.0));
+        for i in 0..1000 {
+            let t = i as f64 / 44100.0;
+            let expected = (2.0 * PI * 440.0 * t).sin() * (2.0 * PI * 30.0 * t).sin();
+            assert!((chain.value_at(t, 440.0, 44100) - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn division_near_zero_stays_smooth() {
+        let mut chain = Chain::with_base(Constant(1.0));
+        chain.div(LFO::sine(1.0));
+        let sound: Vec<f64> = (0..44100)
+            .map(|i| chain.value_at(i as f64 / 44100.0, 440.0, 44100))
+            .collect();
+        assert!(sound.iter().all(|x| x.abs() <= 11.0));
+        assert!(sound.windows(2).all(|w| (w[1] - w[0]).abs() < 0.1));
+
+        // Far enough from zero it is plain division
+        for by in [-0.5, DIV_SOFTNESS, 0.25, 0.5, 2.0] {
+            let mut chain = Chain::with_base(Constant(1.0));
+            chain.div(Constant(by));
+            let quotient = chain.value_at(0.0, 440.0, 44100);
+            assert!(
+                (1.0 / by - quotient).abs() < 1e-6,
+                "{} vs {}",
+                1.0 / by,
+                quotient
+            );
+        }
+    }
+
+    #[test]
+    fn operators() {
+        let mut chain = Chain::new(Oscillator::Sine);
+        chain.mul(Constant(0.5));
+        assert_eq!(0.5, value(&chain));
+        chain.div(Constant(0.25));
+        assert_eq!(2.0, value(&chain));
+        chain.div(Constant(0.0));
+        assert_eq!(0.0, value(&chain));
+        chain.max(Constant(1.75));
+        assert_eq!(1.75, value(&chain));
+        chain.min(Constant(1.5));
+        assert_eq!(1.5, value(&chain));
+        chain.crossfade(Constant(-0.25), 0.5);
+        assert_eq!(0.625, value(&chain));
+    }
 }
@@ -2,20 +2,60 @@ use super::*;
 use crate::oscillator::Oscillator;
 use note::*;
 use simple::Simple;
+use std::f64::consts::FRAC_1_SQRT_2;
 
 pub struct Freq {
     source: Simple,
+    detuned: Simple,
     detune: f64,
+    spread: f64,
 }
 
 impl Signal for Freq {
-    fn value_at(&self, t: f64, frequency: f64) -> f64 {
-        let value = self.source.value_at(t, frequency);
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.value_with(t, frequency, sample_rate, &Params::default())
+    }
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> f64 {
+        let value = self.source.value_with(t, frequency, sample_rate, params);
         if self.detune == 0.0 {
             return value;
         }
-        let new_frequency = frequency + self.detune;
-        value + self.source.value_at(t, new_frequency)
+        let new_frequency = frequency + self.detune * (1.0 + params.detune);
+        value
+            + self
+                .detuned
+                .value_with(t, new_frequency, sample_rate, params)
+    }
+
+    // Source and detuned oscillator pulled apart to opposite sides
+    fn stereo_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> (f64, f64) {
+        if self.spread == 0.0 || self.detune == 0.0 {
+            let value = self.value_with(t, frequency, sample_rate, params);
+            return (value, value);
+        }
+        let value = self.source.value_with(t, frequency, sample_rate, params);
+        let new_frequency = frequency + self.detune * (1.0 + params.detune);
+        let detuned = self
+            .detuned
+            .value_with(t, new_frequency, sample_rate, params);
+
+        // Normalized so that no spread matches the mono sound
+        let (left, right) = crate::stereo::pan(self.spread);
+        let (left, right) = (left / FRAC_1_SQRT_2, right / FRAC_1_SQRT_2);
+        (
+            value * right + detuned * left,
+            value * left + detuned * right,
+        )
+    }
+    fn save(&self, state: &mut Vec<State>) {
+        Signal::save(&self.source, state);
+        Signal::save(&self.detuned, state);
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        Signal::load(&self.source, state);
+        Signal::load(&self.detuned, state);
     }
 }
 
@@ -25,23 +65,44 @@ impl Freq {
     pub fn new(osc: Oscillator, by: f64) -> Self {
         Self {
             source: Simple::new(osc),
+            detuned: Simple::new(osc),
             detune: by,
+            spread: 0.0,
+        }
+    }
+    pub fn phased(osc: Oscillator, by: f64) -> Self {
+        Self {
+            source: Simple::phased(osc),
+            detuned: Simple::phased(osc),
+            detune: by,
+            spread: 0.0,
         }
     }
     pub fn square(by: f64) -> Self {
         Self::new(Oscillator::Square, by)
     }
+
+    // 0.0 keeps both oscillators centred, 1.0 puts them hard left and right
+    pub fn with_spread(mut self, spread: f64) -> Self {
+        self.spread = spread.clamp(0.0, 1.0);
+        self
+    }
 }
 
 pub struct Semitones {
     source: Simple,
+    detuned: Simple,
     detune: i32,
 }
 impl Synth for Semitones {}
 
 impl Signal for Semitones {
-    fn value_at(&self, t: f64, frequency: f64) -> f64 {
-        let value = self.source.value_at(t, frequency);
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.value_with(t, frequency, sample_rate, &Params::default())
+    }
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> f64 {
+        let value = self.source.value_with(t, frequency, sample_rate, params);
         if self.detune == 0 {
             return value;
         }
@@ -56,11 +117,25 @@ impl Signal for Semitones {
         }
         if let Some(base) = base.freq() {
             let diff = proc.unwrap() - base;
-            value + self.source.value_at(t, frequency + diff)
+            let diff = diff * (1.0 + params.detune);
+            value
+                + self
+                    .detuned
+                    .value_with(t, frequency + diff, sample_rate, params)
         } else {
             value
         }
     }
+
+    fn save(&self, state: &mut Vec<State>) {
+        Signal::save(&self.source, state);
+        Signal::save(&self.detuned, state);
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        Signal::load(&self.source, state);
+        Signal::load(&self.detuned, state);
+    }
 }
 impl Semitones {
     fn preprocess_note(&self, note: Note) -> Note {
@@ -103,6 +178,14 @@ impl Semitones {
     pub fn new(osc: Oscillator, by: i32) -> Self {
         Self {
             source: Simple::new(osc),
+            detuned: Simple::new(osc),
+            detune: by,
+        }
+    }
+    pub fn phased(osc: Oscillator, by: i32) -> Self {
+        Self {
+            source: Simple::phased(osc),
+            detuned: Simple::phased(osc),
             detune: by,
         }
     }
@@ -270,4 +353,48 @@ mod tests {
             assert!(false, "preprocess fail");
         }
     }
+
+    #[test]
+    fn spread_splits_channels() {
+        let params = Params::default();
+        let centred = Freq::square(13.0);
+        let spread = Freq::square(13.0).with_spread(1.0);
+
+        let mut differs = false;
+        for i in 0..1000 {
+            let t = i as f64 / 44100.0;
+            let mono = centred.value_at(t, 440.0, 44100);
+            let (l, r) = centred.stereo_with(t, 440.0, 44100, &params);
+            assert!(mono == l && mono == r);
+
+            let (l, r) = spread.stereo_with(t, 440.0, 44100, &params);
+            assert!((mono - (l + r) / 2.0_f64.sqrt()).abs() < 1e-9);
+            differs |= (l - r).abs() > 0.5;
+        }
+        assert!(differs);
+    }
+
+    #[test]
+    fn wrappers_keep_spread() {
+        use crate::generator::{chain::Chain, vibrato::Vibrato};
+        use crate::lfo::LFO;
+
+        let spread = || Freq::square(13.0).with_spread(1.0);
+        let mut chain = Chain::with_base(spread());
+        chain.add(LFO::sine(0.0));
+        let wrappers: Vec<Box<dyn Signal>> = vec![
+            Box::new(Simple::with_source(spread())),
+            Box::new(chain),
+            Box::new(Vibrato::new(spread(), LFO::sine(5.0), 0.0)),
+        ];
+        let params = Params::default();
+        for wrapper in wrappers {
+            for i in 0..1000 {
+                let t = i as f64 / 44100.0;
+                let (l, r) = spread().stereo_with(t, 440.0, 44100, &params);
+                let (wl, wr) = wrapper.stereo_with(t, 440.0, 44100, &params);
+                assert!((l - wl).abs() < 1e-6 && (r - wr).abs() < 1e-6);
+            }
+        }
+    }
 }
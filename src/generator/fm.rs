@@ -0,0 +1,227 @@
+use super::*;
+use crate::envelope::{Envelope, Fixed};
+use std::f64::consts::PI;
+
+pub const MAX_OPERATORS: usize = 8;
+
+// Which operators modulate which: routes always go from a higher operator
+// to a lower one, operator 0 being the main carrier.
+pub enum Algorithm {
+    // Every operator modulates the one below: N -> ... -> 1 -> 0
+    Stack,
+    // Operators modulate in pairs, every even one is a carrier: 1 -> 0, 3 -> 2
+    Pairs,
+    // All operators modulate operator 0
+    Branch,
+    // No modulation, every operator is a carrier
+    Parallel,
+    // (from, to) routes and carriers
+    Custom(Vec<(usize, usize)>, Vec<usize>),
+}
+
+impl Algorithm {
+    fn routes(&self, count: usize) -> (Vec<(usize, usize)>, Vec<usize>) {
+        match self {
+            Self::Stack => ((1..count).map(|x| (x, x - 1)).collect(), vec![0]),
+            Self::Pairs => (
+                (1..count).step_by(2).map(|x| (x, x - 1)).collect(),
+                (0..count).step_by(2).collect(),
+            ),
+            Self::Branch => ((1..count).map(|x| (x, 0)).collect(), vec![0]),
+            Self::Parallel => (Vec::new(), (0..count).collect()),
+            Self::Custom(routes, carriers) => (
+                routes
+                    .iter()
+                    .filter(|(from, to)| from > to && *from < count)
+                    .copied()
+                    .collect(),
+                carriers.iter().filter(|&&x| x < count).copied().collect(),
+            ),
+        }
+    }
+}
+
+pub struct Operator {
+    ratio: f64,
+    index: f64,
+    envelope: Box<dyn Envelope>,
+}
+
+impl Operator {
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            index: 1.0,
+            envelope: Box::new(Fixed {}),
+        }
+    }
+
+    // Modulation depth, in radians, when this operator modulates another
+    pub fn with_index(mut self, index: f64) -> Self {
+        self.index = index;
+        self
+    }
+
+    pub fn with_env_box(mut self, e: Box<dyn Envelope>) -> Self {
+        self.envelope = e;
+        self
+    }
+
+    pub fn with_envelope(self, e: impl Envelope + 'static) -> Self {
+        self.with_env_box(Box::new(e))
+    }
+}
+
+pub struct FM {
+    operators: Vec<Operator>,
+    routes: Vec<(usize, usize)>,
+    carriers: Vec<usize>,
+    algorithm: Algorithm,
+}
+
+impl Signal for FM {
+    fn value_at(&self, t: f64, frequency: f64, _sample_rate: i32) -> f64 {
+        if self.carriers.is_empty() {
+            return 0.0;
+        }
+
+        let mut outputs = [0.0; MAX_OPERATORS];
+        for (i, op) in self.operators.iter().enumerate().rev() {
+            let modulation: f64 = self
+                .routes
+                .iter()
+                .filter(|(_, to)| *to == i)
+                .map(|&(from, _)| self.operators[from].index * outputs[from])
+                .sum();
+            let phase = 2.0 * PI * op.ratio * frequency * t + modulation;
+            outputs[i] = op.envelope.value_at(t, 1.0) * phase.sin();
+        }
+
+        let mix: f64 = self.carriers.iter().map(|&x| outputs[x]).sum();
+        mix / self.carriers.len() as f64
+    }
+}
+
+impl Synth for FM {}
+
+impl FM {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            operators: Vec::new(),
+            routes: Vec::new(),
+            carriers: Vec::new(),
+            algorithm,
+        }
+    }
+
+    // Adds the next operator, up to MAX_OPERATORS. Debug builds panic on
+    // any past that, release builds leave them out.
+    pub fn operator(mut self, op: Operator) -> Self {
+        debug_assert!(
+            self.operators.len() < MAX_OPERATORS,
+            "FM takes at most {} operators",
+            MAX_OPERATORS
+        );
+        if self.operators.len() < MAX_OPERATORS {
+            self.operators.push(op);
+            let (routes, carriers) = self.algorithm.routes(self.operators.len());
+            self.routes = routes;
+            self.carriers = carriers;
+        }
+        self
+    }
+
+    pub fn bell() -> Self {
+        use crate::envelope::{curve::Curve, RAR};
+        let decay =
+            |release| RAR::new(0.0, release).with_curves(Curve::Linear, Curve::Logarithmic(4.0));
+        Self::new(Algorithm::Pairs)
+            .operator(Operator::new(1.0).with_envelope(decay(2.0)))
+            .operator(Operator::new(3.5).with_index(4.0).with_envelope(decay(1.2)))
+            .operator(Operator::new(2.0).with_envelope(decay(1.5)))
+            .operator(Operator::new(7.0).with_index(2.5).with_envelope(decay(0.6)))
+    }
+
+    pub fn bass() -> Self {
+        use crate::envelope::{curve::Curve, RAR};
+        Self::new(Algorithm::Stack)
+            .operator(Operator::new(1.0))
+            .operator(Operator::new(1.0).with_index(2.0).with_envelope(
+                RAR::new(0.0, 0.3).with_curves(Curve::Linear, Curve::Logarithmic(3.0)),
+            ))
+            .operator(Operator::new(2.0).with_index(0.8))
+            .operator(Operator::new(0.5).with_index(0.3))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(t: f64, frequency: f64) -> f64 {
+        (2.0 * PI * frequency * t).sin()
+    }
+
+    #[test]
+    fn unmodulated_carrier_is_sine() {
+        let fm = FM::new(Algorithm::Stack)
+            .operator(Operator::new(2.0))
+            .operator(Operator::new(3.0).with_index(0.0));
+        for i in 0..1000 {
+            let t = i as f64 / 44100.0;
+            assert!((fm.value_at(t, 220.0, 44100) - sine(t, 440.0)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn modulator_bends_phase() {
+        let fm = FM::new(Algorithm::Stack)
+            .operator(Operator::new(1.0))
+            .operator(Operator::new(2.0).with_index(1.5));
+        let t = 0.001;
+        let expected = (2.0 * PI * 220.0 * t + 1.5 * sine(t, 440.0)).sin();
+        assert!((fm.value_at(t, 220.0, 44100) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn algorithms_route_operators() {
+        assert_eq!(
+            (vec![(1, 0), (2, 1), (3, 2)], vec![0]),
+            Algorithm::Stack.routes(4)
+        );
+        assert_eq!(
+            (vec![(1, 0), (3, 2)], vec![0, 2]),
+            Algorithm::Pairs.routes(4)
+        );
+        assert_eq!(
+            (vec![(1, 0), (2, 0), (3, 0)], vec![0]),
+            Algorithm::Branch.routes(4)
+        );
+        assert_eq!((vec![], vec![0, 1, 2, 3]), Algorithm::Parallel.routes(4));
+        assert_eq!(
+            (vec![(3, 1)], vec![0, 1]),
+            Algorithm::Custom(vec![(3, 1), (0, 2), (5, 0)], vec![0, 1, 7]).routes(4)
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "at most 8 operators")]
+    fn rejects_operators_past_limit() {
+        let fm = (0..MAX_OPERATORS).fold(FM::new(Algorithm::Stack), |fm, i| {
+            fm.operator(Operator::new(i as f64 + 1.0))
+        });
+        assert_eq!(MAX_OPERATORS, fm.operators.len());
+        fm.operator(Operator::new(0.5));
+    }
+
+    #[test]
+    fn presets_stay_in_range() {
+        use note::*;
+        for fm in [FM::bell(), FM::bass()] {
+            let sound = fm.play(120.0, note![A: C3, 1 / 4], 44100);
+            assert!(sound.iter().all(|x| x.abs() <= 1.0));
+            assert!(sound.iter().any(|x| x.abs() > 0.5));
+        }
+    }
+}
@@ -1,38 +1,153 @@
 pub mod chain;
 pub mod detuned;
+pub mod fm;
+pub mod noise;
+pub mod pulse;
 pub mod simple;
+pub mod vibrato;
+pub mod wavetable;
 
-use crate::SAMPLE_RATE;
+use crate::modulation::Params;
 use note::Note;
+use std::slice::Iter;
 
 pub trait Generator {
-    fn play(&self, bpm: f64, note: Note) -> Vec<f64>;
+    fn process(&self, note: Note, offset: usize, sample_rate: i32, buffer: &mut [f64]);
+
+    // Renders the note at the given (possibly warped) times, with per-sample
+    // modulation parameters.
+    fn process_with(
+        &self,
+        note: Note,
+        times: &[f64],
+        params: &[Params],
+        sample_rate: i32,
+        buffer: &mut [f64],
+    );
+
+    // Renders both channels, which are the same unless the generator has
+    // some stereo spread of its own.
+    fn process_stereo(
+        &self,
+        note: Note,
+        times: &[f64],
+        params: &[Params],
+        sample_rate: i32,
+        left: &mut [f64],
+        right: &mut [f64],
+    ) {
+        self.process_with(note, times, params, sample_rate, left);
+        right.copy_from_slice(left);
+    }
+
+    fn play(&self, bpm: f64, note: Note, sample_rate: i32) -> Vec<f64> {
+        let mut samples = vec![0.0; crate::samples(note.secs(bpm), sample_rate)];
+        self.process(note, 0, sample_rate, &mut samples);
+        samples
+    }
+
+    // See `Signal::save`
+    fn save(&self, _state: &mut Vec<State>) {}
+    fn load(&self, _state: &mut Iter<State>) {}
 }
 
 pub trait Signal {
-    fn value_at(&self, t: f64, frequency: f64) -> f64;
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64;
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, _params: &Params) -> f64 {
+        self.value_at(t, frequency, sample_rate)
+    }
+
+    // Left and right values
+    fn stereo_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> (f64, f64) {
+        let value = self.value_with(t, frequency, sample_rate, params);
+        (value, value)
+    }
+
+    // Running state of stateful signals (phases, clocks, noise). Every
+    // voice keeps its own copy and swaps it in around its renders, so
+    // overlapping notes don't share it. Loading from an exhausted state, or
+    // one left by some other kind of signal, starts the signal over.
+    // Containers pass these on to their sources.
+    fn save(&self, _state: &mut Vec<State>) {}
+    fn load(&self, _state: &mut Iter<State>) {}
+}
+
+// What a stateful signal saves, see `Signal::save`
+#[derive(Copy, Clone, Debug)]
+pub enum State {
+    // Phase or warped time, and the time it was last moved on to
+    Clock(f64, Option<f64>),
+    Noise(noise::NoiseState, Option<f64>),
 }
 
 pub trait Synth: Signal {
-    fn play_note(&self, bpm: f64, note: Note) -> Vec<f64> {
+    fn process_note(&self, note: Note, offset: usize, sample_rate: i32, buffer: &mut [f64]) {
+        let note = self.preprocess_note(note);
+        let frequency = note.freq().unwrap_or(0.0);
+        if frequency == 0.0 {
+            buffer.fill(0.0);
+            return;
+        }
+
+        buffer.iter_mut().enumerate().for_each(|(i, x)| {
+            let t = (offset + i) as f64 / sample_rate as f64;
+            *x = self.value_at(t, frequency, sample_rate);
+        });
+    }
+
+    fn process_note_with(
+        &self,
+        note: Note,
+        times: &[f64],
+        params: &[Params],
+        sample_rate: i32,
+        buffer: &mut [f64],
+    ) {
         let note = self.preprocess_note(note);
-        let duration = note.secs(bpm);
-        let frequency = if let Some(f) = note.freq() { f } else { 0.0 };
+        let frequency = note.freq().unwrap_or(0.0);
+        if frequency == 0.0 {
+            buffer.fill(0.0);
+            return;
+        }
 
-        let sample_duration = (SAMPLE_RATE as f64 * duration).floor() as usize;
-        let samples: Vec<f64> = vec![0.0; sample_duration];
+        buffer
+            .iter_mut()
+            .zip(times.iter().zip(params.iter()))
+            .for_each(|(x, (&t, params))| {
+                *x = self.value_with(t, frequency, sample_rate, params);
+            });
+    }
+
+    fn process_note_stereo(
+        &self,
+        note: Note,
+        times: &[f64],
+        params: &[Params],
+        sample_rate: i32,
+        left: &mut [f64],
+        right: &mut [f64],
+    ) {
+        let note = self.preprocess_note(note);
+        let frequency = note.freq().unwrap_or(0.0);
         if frequency == 0.0 {
-            return samples;
+            left.fill(0.0);
+            right.fill(0.0);
+            return;
         }
 
+        left.iter_mut()
+            .zip(right.iter_mut())
+            .zip(times.iter().zip(params.iter()))
+            .for_each(|((l, r), (&t, params))| {
+                (*l, *r) = self.stereo_with(t, frequency, sample_rate, params);
+            });
+    }
+
+    fn play_note(&self, bpm: f64, note: Note, sample_rate: i32) -> Vec<f64> {
+        let mut samples = vec![0.0; crate::samples(note.secs(bpm), sample_rate)];
+        self.process_note(note, 0, sample_rate, &mut samples);
         samples
-            .iter()
-            .enumerate()
-            .map(|(i, _)| {
-                let t = i as f64 / SAMPLE_RATE as f64;
-                self.value_at(t, frequency)
-            })
-            .collect()
     }
 
     fn preprocess_note(&self, note: Note) -> Note {
@@ -44,8 +159,46 @@ impl<T> Generator for T
 where
     T: Synth,
 {
-    fn play(&self, bpm: f64, note: Note) -> Vec<f64> {
+    fn process(&self, note: Note, offset: usize, sample_rate: i32, buffer: &mut [f64]) {
+        let s: &dyn Synth = self;
+        s.process_note(note, offset, sample_rate, buffer)
+    }
+
+    fn process_with(
+        &self,
+        note: Note,
+        times: &[f64],
+        params: &[Params],
+        sample_rate: i32,
+        buffer: &mut [f64],
+    ) {
         let s: &dyn Synth = self;
-        s.play_note(bpm, note)
+        s.process_note_with(note, times, params, sample_rate, buffer)
+    }
+
+    fn process_stereo(
+        &self,
+        note: Note,
+        times: &[f64],
+        params: &[Params],
+        sample_rate: i32,
+        left: &mut [f64],
+        right: &mut [f64],
+    ) {
+        let s: &dyn Synth = self;
+        s.process_note_stereo(note, times, params, sample_rate, left, right)
+    }
+
+    fn play(&self, bpm: f64, note: Note, sample_rate: i32) -> Vec<f64> {
+        let s: &dyn Synth = self;
+        s.play_note(bpm, note, sample_rate)
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        Signal::save(self, state)
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        Signal::load(self, state)
     }
 }
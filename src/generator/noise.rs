@@ -0,0 +1,175 @@
+use super::*;
+use std::cell::Cell;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Color {
+    White,
+    Pink,
+    Brown,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoiseState {
+    rng: u64,
+    pink: [f64; 7],
+    brown: f64,
+    value: f64,
+}
+
+// Seeded noise source. Each sample steps the generator on from the last, so
+// it goes back to the seed whenever time goes backwards, and every voice
+// steps its own copy. That way each note renders the same way.
+pub struct Noise {
+    color: Color,
+    seed: u64,
+    state: Cell<NoiseState>,
+    last: Cell<Option<f64>>,
+}
+
+impl Signal for Noise {
+    fn value_at(&self, t: f64, _frequency: f64, _sample_rate: i32) -> f64 {
+        let mut state = match self.last.get() {
+            Some(last) if t == last => return self.state.get().value,
+            Some(last) if t > last => self.state.get(),
+            _ => self.initial(),
+        };
+        let white = state.white();
+        state.value = match self.color {
+            Color::White => white,
+            Color::Pink => state.pink(white),
+            Color::Brown => state.brown(white),
+        };
+        self.state.set(state);
+        self.last.set(Some(t));
+        state.value
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        state.push(State::Noise(self.state.get(), self.last.get()));
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        match state.next() {
+            Some(&State::Noise(current, last)) => {
+                self.state.set(current);
+                self.last.set(last);
+            }
+            _ => self.last.set(None),
+        }
+    }
+}
+
+impl Synth for Noise {}
+
+impl Noise {
+    pub fn new(color: Color) -> Self {
+        Self {
+            color,
+            seed: 0x2545_f491_4f6c_dd1d,
+            state: Cell::new(NoiseState::default()),
+            last: Cell::new(None),
+        }
+    }
+
+    pub fn white() -> Self {
+        Self::new(Color::White)
+    }
+
+    pub fn pink() -> Self {
+        Self::new(Color::Pink)
+    }
+
+    pub fn brown() -> Self {
+        Self::new(Color::Brown)
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn initial(&self) -> NoiseState {
+        NoiseState {
+            // xorshift gets stuck on zero
+            rng: self.seed.max(1),
+            ..Default::default()
+        }
+    }
+}
+
+impl NoiseState {
+    // xorshift64*, scaled to -1.0 - 1.0
+    fn white(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let x = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        (x as f64 / (1u64 << 53) as f64) * 2.0 - 1.0
+    }
+
+    // Paul Kellet's refined pink noise filter
+    fn pink(&mut self, white: f64) -> f64 {
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b.iter().sum::<f64>() + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.11
+    }
+
+    // Leaky integration of white noise
+    fn brown(&mut self, white: f64) -> f64 {
+        self.brown = (self.brown + 0.02 * white) / 1.02;
+        self.brown * 3.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use note::*;
+
+    fn roughness(sound: &[f64]) -> f64 {
+        sound.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / sound.len() as f64
+    }
+
+    #[test]
+    fn seeded_renders_are_reproducible() {
+        let note = note![A: C4, 1 / 4];
+        let noise = Noise::pink().with_seed(13);
+        let first = noise.play(120.0, note, 44100);
+        let second = noise.play(120.0, note, 44100);
+        assert_eq!(first, second);
+
+        let other = Noise::pink().with_seed(12).play(120.0, note, 44100);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn overlapping_voices_keep_their_own_noise() {
+        use crate::envelope::RAR;
+        use crate::testing::assert_chord_is_sum;
+        use crate::Instrument;
+
+        let synth = Instrument::new(Noise::brown(), RAR::new(0.01, 0.05));
+        assert_chord_is_sum(&synth, &[note![A: C4, 1 / 8], note![C: C4, 1 / 16]], 1.0);
+    }
+
+    #[test]
+    fn colors_stay_in_range_and_darken() {
+        let note = note![A: C4, 1 / 1];
+        let white = Noise::white().play(120.0, note, 44100);
+        let pink = Noise::pink().play(120.0, note, 44100);
+        let brown = Noise::brown().play(120.0, note, 44100);
+
+        for sound in [&white, &pink, &brown] {
+            assert!(sound.iter().all(|x| x.abs() <= 1.0));
+        }
+        assert!(roughness(&white) > roughness(&pink));
+        assert!(roughness(&pink) > roughness(&brown));
+    }
+}
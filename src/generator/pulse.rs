@@ -0,0 +1,144 @@
+use super::*;
+use crate::oscillator::Osc;
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct Pulse {
+    width: f64,
+    modulation: Option<(Box<dyn Signal>, f64)>,
+}
+
+impl Default for Pulse {
+    fn default() -> Self {
+        Self::new(crate::oscillator::PULSE_WIDTH)
+    }
+}
+
+impl Signal for Pulse {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.value_with(t, frequency, sample_rate, &Params::default())
+    }
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> f64 {
+        let width = self.width_at(t, frequency, sample_rate) + params.pulse_width;
+        Osc::Pulse(frequency, width.clamp(0.0, 1.0)).sample(t, sample_rate)
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        if let Some((signal, _)) = &self.modulation {
+            signal.save(state);
+        }
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        if let Some((signal, _)) = &self.modulation {
+            signal.load(state);
+        }
+    }
+}
+
+impl Synth for Pulse {}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl Pulse {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(width: f64) -> Self {
+        Self {
+            width: width.clamp(0.0, 1.0),
+            modulation: None,
+        }
+    }
+
+    pub fn square() -> Self {
+        Self::new(0.5)
+    }
+
+    // Duty cycle before modulation, 0.0 - 1.0
+    pub fn width(&self) -> f64 {
+        self.width
+    }
+
+    pub fn set_width(&mut self, width: f64) {
+        self.width = width.clamp(0.0, 1.0);
+    }
+}
+
+impl Pulse {
+    // Sweeps the width by `depth` in each direction, following the modulator
+    pub fn modulate(self, what: impl Signal + 'static, depth: f64) -> Self {
+        self.modulate_box(Box::new(what), depth)
+    }
+
+    pub fn modulate_box(mut self, what: Box<dyn Signal>, depth: f64) -> Self {
+        self.modulation = Some((what, depth));
+        self
+    }
+
+    pub fn width_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        let width = match &self.modulation {
+            Some((signal, depth)) => {
+                self.width + depth * signal.value_at(t, frequency, sample_rate)
+            }
+            None => self.width,
+        };
+        width.clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lfo::LFO;
+    use crate::oscillator::{Oscillator, PULSE_WIDTH};
+
+    fn duty_cycle(pulse: &Pulse, from: usize, to: usize) -> f64 {
+        let high = (from..to)
+            .filter(|&i| pulse.value_at(i as f64 / 44100.0, 100.0, 44100) > 0.0)
+            .count();
+        high as f64 / (to - from) as f64
+    }
+
+    #[test]
+    fn width_sets_duty_cycle() {
+        for width in [0.1, 0.25, 0.5, 0.8] {
+            let duty = duty_cycle(&Pulse::new(width), 0, 44100);
+            assert!((duty - width).abs() < 0.01, "{} vs {}", duty, width);
+        }
+    }
+
+    #[test]
+    fn oscillator_uses_default_width() {
+        let high = (0..44100)
+            .filter(|&i| Oscillator::from(7).value_at(i as f64 / 44100.0, 100.0, 44100) > 0.0)
+            .count();
+        assert!((high as f64 / 44100.0 - PULSE_WIDTH).abs() < 0.01);
+
+        let mut pulse = Pulse::default();
+        pulse.set_width(0.7);
+        assert_eq!(0.7, pulse.width());
+        assert!((duty_cycle(&pulse, 0, 44100) - 0.7).abs() < 0.01);
+    }
+
+    // Shapes and the pulse generator both have to cross the wasm boundary
+    #[cfg(feature = "wasm")]
+    #[test]
+    fn exported_to_wasm() {
+        use wasm_bindgen::convert::{FromWasmAbi, IntoWasmAbi};
+
+        fn exported<T: IntoWasmAbi + FromWasmAbi>() {}
+        exported::<Oscillator>();
+        exported::<Pulse>();
+    }
+
+    #[test]
+    fn width_follows_lfo() {
+        let pulse = Pulse::new(0.5).modulate(LFO::square(1.0), 0.3);
+
+        let first = duty_cycle(&pulse, 0, 22050);
+        let second = duty_cycle(&pulse, 22050, 44100);
+        assert!((first - 0.8).abs() < 0.01, "{}", first);
+        assert!((second - 0.2).abs() < 0.01, "{}", second);
+    }
+}
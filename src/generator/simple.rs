@@ -1,21 +1,35 @@
 use super::*;
-use crate::oscillator::Oscillator;
+use crate::oscillator::{Oscillator, Phasor};
 
 pub struct Simple {
-    osc: Oscillator,
+    source: Box<dyn Signal>,
 }
 
 impl Default for Simple {
     fn default() -> Self {
-        Self {
-            osc: Oscillator::Sine,
-        }
+        Self::new(Oscillator::Sine)
     }
 }
 
 impl Signal for Simple {
-    fn value_at(&self, t: f64, frequency: f64) -> f64 {
-        self.osc.get(frequency).at(t)
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.source.value_at(t, frequency, sample_rate)
+    }
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> f64 {
+        self.source.value_with(t, frequency, sample_rate, params)
+    }
+
+    fn stereo_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> (f64, f64) {
+        self.source.stereo_with(t, frequency, sample_rate, params)
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        self.source.save(state)
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        self.source.load(state)
     }
 }
 
@@ -23,7 +37,17 @@ impl Synth for Simple {}
 
 impl Simple {
     pub fn new(osc: Oscillator) -> Self {
-        Self { osc }
+        Self::with_source(osc)
+    }
+
+    pub fn phased(osc: Oscillator) -> Self {
+        Self::with_source(Phasor::new(osc))
+    }
+
+    pub fn with_source(source: impl Signal + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+        }
     }
 
     pub fn square() -> Self {
@@ -43,8 +67,22 @@ mod tests {
         let osc = Simple::default();
 
         let start = Instant::now();
-        osc.play(3.0, pause![1 / 1]);
-        osc.play(3.0, note![A: C0, 1 / 1]);
+        osc.play(3.0, pause![1 / 1], crate::SAMPLE_RATE);
+        osc.play(3.0, note![A: C0, 1 / 1], crate::SAMPLE_RATE);
         eprintln!("duration: {}ms", start.elapsed().as_millis());
     }
+
+    #[test]
+    fn sample_rate_sets_length() {
+        use note::*;
+
+        let osc = Simple::default();
+        let note = note![A: C4, 1 / 4];
+        let secs = note.secs(120.0);
+
+        for rate in [44100, 48000, 96000] {
+            let sound = osc.play(120.0, note, rate);
+            assert_eq!((rate as f64 * secs).floor() as usize, sound.len());
+        }
+    }
 }
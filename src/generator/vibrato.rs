@@ -0,0 +1,165 @@
+use super::*;
+use std::cell::Cell;
+
+// Pitch modulation for any signal. Rather than bending the frequency passed
+// down, which would make stateless oscillators jump around, it runs the
+// source on a warped clock that speeds up and slows down with the modulator.
+// The warped clock advances a step per sample, so it starts over whenever
+// time goes backwards, and every voice runs its own.
+pub struct Vibrato {
+    source: Box<dyn Signal>,
+    lfo: Box<dyn Signal>,
+    cents: f64,
+    warped: Cell<f64>,
+    last: Cell<Option<f64>>,
+}
+
+impl Signal for Vibrato {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.value_with(t, frequency, sample_rate, &Params::default())
+    }
+
+    fn value_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> f64 {
+        let warped = self.warp(t, frequency, sample_rate);
+        self.source
+            .value_with(warped, frequency, sample_rate, params)
+    }
+
+    fn stereo_with(&self, t: f64, frequency: f64, sample_rate: i32, params: &Params) -> (f64, f64) {
+        let warped = self.warp(t, frequency, sample_rate);
+        self.source
+            .stereo_with(warped, frequency, sample_rate, params)
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        state.push(State::Clock(self.warped.get(), self.last.get()));
+        self.source.save(state);
+        self.lfo.save(state);
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        let (warped, last) = match state.next() {
+            Some(&State::Clock(warped, last)) => (warped, last),
+            _ => (0.0, None),
+        };
+        self.warped.set(warped);
+        self.last.set(last);
+        self.source.load(state);
+        self.lfo.load(state);
+    }
+}
+
+impl Synth for Vibrato {}
+
+impl Vibrato {
+    pub fn new(source: impl Signal + 'static, lfo: impl Signal + 'static, cents: f64) -> Self {
+        Self::new_boxed(Box::new(source), Box::new(lfo), cents)
+    }
+
+    pub fn new_boxed(source: Box<dyn Signal>, lfo: Box<dyn Signal>, cents: f64) -> Self {
+        Self {
+            source,
+            lfo,
+            cents,
+            warped: Cell::new(0.0),
+            last: Cell::new(None),
+        }
+    }
+
+    pub fn semitones(source: impl Signal + 'static, lfo: impl Signal + 'static, by: f64) -> Self {
+        Self::new(source, lfo, by * 100.0)
+    }
+
+    pub fn ratio_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        let cents = self.cents * self.lfo.value_at(t, frequency, sample_rate);
+        2.0_f64.powf(cents / 1200.0)
+    }
+
+    // Moves the clock on to `t`
+    fn warp(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        let warped = match self.last.get() {
+            Some(last) if t >= last => {
+                self.warped.get() + (t - last) * self.ratio_at(t, frequency, sample_rate)
+            }
+            _ => t,
+        };
+        self.warped.set(warped);
+        self.last.set(Some(t));
+        warped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{chain::Chain, detuned, simple::Simple};
+    use crate::lfo::LFO;
+    use crate::oscillator::Oscillator;
+    use crate::testing::{assert_chord_is_sum, crossings, Constant};
+
+    fn render(signal: &dyn Signal, frequency: f64) -> Vec<f64> {
+        (0..44100)
+            .map(|i| signal.value_at(i as f64 / 44100.0, frequency, 44100))
+            .collect()
+    }
+
+    #[test]
+    fn shifts_pitch_by_cents() {
+        let up = Vibrato::new(Simple::default(), Constant(1.0), 1200.0);
+        let octave = render(&Simple::default(), 440.0);
+        let shifted = render(&up, 220.0);
+        for (a, b) in octave.iter().zip(shifted.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        let down = Vibrato::semitones(Simple::default(), Constant(-1.0), 12.0);
+        assert_eq!(220, crossings(&render(&down, 440.0)));
+    }
+
+    #[test]
+    fn wobbles_around_pitch() {
+        let vibrato = Vibrato::new(Simple::default(), LFO::sine(5.0), 100.0);
+        let sound = render(&vibrato, 440.0);
+        assert!((crossings(&sound) as i32 - 440).abs() <= 1);
+
+        // Rising half of the first LFO cycle runs sharp, falling half flat
+        let sharp = crossings(&sound[..4410]);
+        let flat = crossings(&sound[4410..8820]);
+        assert!(sharp > 44 && flat < 44, "{} / {}", sharp, flat);
+    }
+
+    #[test]
+    fn works_for_every_generator() {
+        let mut chain = Chain::new(Oscillator::Saw);
+        chain.add(LFO::sine(3.0));
+        let sources: Vec<Box<dyn Signal>> = vec![
+            Box::new(Simple::square()),
+            Box::new(chain),
+            Box::new(detuned::Freq::square(3.0)),
+            Box::new(detuned::Semitones::square(7)),
+        ];
+        // An octave up doubles the rate the source runs at, an octave down
+        // halves it
+        for source in sources {
+            let plain = crossings(&render(source.as_ref(), 220.0)) as f64;
+            let up = Vibrato::new_boxed(source, Box::new(Constant(1.0)), 1200.0);
+            let ratio = crossings(&render(&up, 220.0)) as f64 / plain;
+            assert!((ratio - 2.0).abs() < 0.05, "{}", ratio);
+
+            let down = Vibrato::new_boxed(Box::new(up), Box::new(Constant(-1.0)), 2400.0);
+            let ratio = crossings(&render(&down, 220.0)) as f64 / plain;
+            assert!((ratio - 0.5).abs() < 0.05, "{}", ratio);
+        }
+    }
+
+    #[test]
+    fn overlapping_voices_keep_their_own_clock() {
+        use crate::envelope::RAR;
+        use crate::Instrument;
+        use note::*;
+
+        let vibrato = Vibrato::new(Simple::default(), LFO::sine(5.0), 100.0);
+        let synth = Instrument::new(vibrato, RAR::new(0.01, 0.05));
+        assert_chord_is_sum(&synth, &[note![A: C4, 1 / 8], note![E: C4, 1 / 8]], 1.0);
+    }
+}
@@ -0,0 +1,254 @@
+use super::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// Plays single-cycle waveforms, morphing between neighbouring tables
+// according to position (0.0 is the first table, 1.0 the last one).
+pub struct Wavetable {
+    tables: Vec<Vec<f64>>,
+    position: f64,
+    modulation: Option<(Box<dyn Signal>, f64)>,
+}
+
+impl Signal for Wavetable {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        let phase = (frequency * t).rem_euclid(1.0);
+        let position = self.position_at(t, frequency, sample_rate) * (self.tables.len() - 1) as f64;
+
+        let index = position.floor() as usize;
+        let current = lookup(&self.tables[index], phase);
+        if index + 1 >= self.tables.len() {
+            return current;
+        }
+        let next = lookup(&self.tables[index + 1], phase);
+        current + (next - current) * position.fract()
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        if let Some((signal, _)) = &self.modulation {
+            signal.save(state);
+        }
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        if let Some((signal, _)) = &self.modulation {
+            signal.load(state);
+        }
+    }
+}
+
+impl Synth for Wavetable {}
+
+impl Wavetable {
+    pub fn new(table: Vec<f64>) -> Self {
+        Self::morph(vec![table])
+    }
+
+    pub fn morph(tables: Vec<Vec<f64>>) -> Self {
+        let mut tables: Vec<Vec<f64>> = tables.into_iter().filter(|x| !x.is_empty()).collect();
+        if tables.is_empty() {
+            tables.push(vec![0.0]);
+        }
+        Self {
+            tables,
+            position: 0.0,
+            modulation: None,
+        }
+    }
+
+    // Reads consecutive single-cycle tables of `table_size` frames from the
+    // first channel of a PCM or float WAV file. Zero uses the whole file as
+    // a single table.
+    pub fn from_wav(path: impl AsRef<Path>, table_size: usize) -> std::io::Result<Self> {
+        let samples = read_wav(&std::fs::read(path)?)?;
+        let tables = if table_size == 0 {
+            vec![samples]
+        } else {
+            samples
+                .chunks_exact(table_size)
+                .map(|x| x.to_vec())
+                .collect()
+        };
+        if tables.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "not enough samples"));
+        }
+        Ok(Self::morph(tables))
+    }
+
+    pub fn with_position(mut self, position: f64) -> Self {
+        self.position = position;
+        self
+    }
+
+    // Sweeps the position by `depth` in each direction, following the modulator
+    pub fn modulate(self, what: impl Signal + 'static, depth: f64) -> Self {
+        self.modulate_box(Box::new(what), depth)
+    }
+
+    pub fn modulate_box(mut self, what: Box<dyn Signal>, depth: f64) -> Self {
+        self.modulation = Some((what, depth));
+        self
+    }
+
+    pub fn position_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        let position = match &self.modulation {
+            Some((signal, depth)) => {
+                self.position + depth * signal.value_at(t, frequency, sample_rate)
+            }
+            None => self.position,
+        };
+        position.clamp(0.0, 1.0)
+    }
+}
+
+fn lookup(table: &[f64], phase: f64) -> f64 {
+    let position = phase * table.len() as f64;
+    let index = position.floor() as usize % table.len();
+    let next = (index + 1) % table.len();
+    table[index] + (table[next] - table[index]) * position.fract()
+}
+
+fn read_wav(data: &[u8]) -> std::io::Result<Vec<f64>> {
+    let invalid = |msg| Error::new(ErrorKind::InvalidData, msg);
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let body = &data[pos + 8..(pos + 8 + size).min(data.len())];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let tag = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                let bits = u16::from_le_bytes([body[14], body[15]]) as usize;
+                format = Some((tag, channels.max(1), bits));
+            }
+            b"data" => {
+                let (tag, channels, bits) = format.ok_or_else(|| invalid("missing format"))?;
+                let width = bits / 8;
+                let frame = width * channels;
+                if width == 0 || body.len() < frame {
+                    return Err(invalid("missing sample data"));
+                }
+                return body
+                    .chunks_exact(frame)
+                    .map(|x| decode(tag, &x[..width]).ok_or_else(|| invalid("unsupported format")))
+                    .collect();
+            }
+            _ => {}
+        }
+        pos += 8 + size + size % 2;
+    }
+    Err(invalid("missing sample data"))
+}
+
+fn decode(tag: u16, bytes: &[u8]) -> Option<f64> {
+    match (tag, bytes.len()) {
+        (1, 1) => Some((bytes[0] as f64 - 128.0) / 128.0),
+        (1, 2) => Some(i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / 32768.0),
+        (1, 3) => {
+            let x = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+            Some(x as f64 / 8388608.0)
+        }
+        (1, 4) => {
+            Some(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 2147483648.0)
+        }
+        (3, 4) => Some(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64),
+        (3, 8) => {
+            let mut x = [0; 8];
+            x.copy_from_slice(bytes);
+            Some(f64::from_le_bytes(x))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn sine(size: usize) -> Vec<f64> {
+        (0..size)
+            .map(|i| (2.0 * PI * i as f64 / size as f64).sin())
+            .collect()
+    }
+
+    #[test]
+    fn plays_single_cycle() {
+        let table = Wavetable::new(sine(2048));
+        for i in 0..1000 {
+            let t = i as f64 / 44100.0;
+            let expected = (2.0 * PI * 440.0 * t).sin();
+            assert!((table.value_at(t, 440.0, 44100) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn morphs_between_tables() {
+        let tables = vec![vec![1.0; 16], vec![0.0; 16], vec![-1.0; 16]];
+        assert_eq!(
+            1.0,
+            Wavetable::morph(tables.clone()).value_at(0.1, 440.0, 44100)
+        );
+        assert_eq!(
+            0.5,
+            Wavetable::morph(tables.clone())
+                .with_position(0.25)
+                .value_at(0.1, 440.0, 44100)
+        );
+        assert_eq!(
+            -1.0,
+            Wavetable::morph(tables)
+                .with_position(1.0)
+                .value_at(0.1, 440.0, 44100)
+        );
+    }
+
+    #[test]
+    fn loads_wav_tables() {
+        let samples: Vec<i16> = sine(64)
+            .iter()
+            .chain(vec![0.5; 64].iter())
+            .map(|x| (x * 32767.0) as i16)
+            .collect();
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + samples.len() as u32 * 2).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        for x in [1u16, 1] {
+            wav.extend_from_slice(&x.to_le_bytes());
+        }
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&88200u32.to_le_bytes());
+        for x in [2u16, 16] {
+            wav.extend_from_slice(&x.to_le_bytes());
+        }
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(samples.len() as u32 * 2).to_le_bytes());
+        samples
+            .iter()
+            .for_each(|x| wav.extend_from_slice(&x.to_le_bytes()));
+
+        let path = std::env::temp_dir().join("instrument-wavetable-test.wav");
+        std::fs::write(&path, wav).expect("temp file should be writable");
+        let table = Wavetable::from_wav(&path, 64).expect("wav should load");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(2, table.tables.len());
+        assert!((table.value_at(0.25 / 440.0, 440.0, 44100) - 1.0).abs() < 1e-3);
+        let last = table.with_position(1.0);
+        assert!((last.value_at(0.1, 440.0, 44100) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(read_wav(b"definitely not a wav file").is_err());
+    }
+}
@@ -31,7 +31,7 @@ impl LFO {
 }
 
 impl Signal for LFO {
-    fn value_at(&self, t: f64, _frequency: f64) -> f64 {
+    fn value_at(&self, t: f64, _frequency: f64, _sample_rate: i32) -> f64 {
         self.shape.at(t)
     }
 }
@@ -78,7 +78,7 @@ impl ELFO {
 }
 
 impl Signal for ELFO {
-    fn value_at(&self, t: f64, frequency: f64) -> f64 {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
         let cycle_length = self.envelope.min();
         let diff = if t > cycle_length {
             t - ((t / cycle_length).floor() * cycle_length)
@@ -86,6 +86,6 @@ impl Signal for ELFO {
             t
         };
 
-        self.envelope.value_at(diff, 1.0) * self.lfo.value_at(t, frequency)
+        self.envelope.value_at(diff, 1.0) * self.lfo.value_at(t, frequency, sample_rate)
     }
 }
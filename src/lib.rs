@@ -7,13 +7,54 @@ use generator::*;
 pub mod envelope;
 use envelope::*;
 
+pub mod voice;
+use voice::*;
+
+pub mod poly;
+use poly::*;
+
+pub mod sequence;
+use sequence::{Sequence, Tempo};
+
+pub mod song;
+
+pub mod stereo;
+use stereo::Stereo;
+
+pub mod master;
+use master::{Levels, Master};
+
+pub mod mixer;
+use mixer::{Buffer, Bus, Channel, Router};
+
+pub mod modulation;
+use modulation::{Destination, Matrix, Source};
+
+pub mod filter;
+use filter::Filter;
+
+pub mod effect;
+use effect::{Effect, Effects};
+
+#[cfg(test)]
+mod testing;
+
 use note::Note;
+use std::cell::Cell;
 
 pub const SAMPLE_RATE: i32 = 44100;
 
+pub fn samples(secs: f64, sample_rate: i32) -> usize {
+    (sample_rate as f64 * secs).floor() as usize
+}
+
 pub struct Instrument {
     generator: Box<dyn Generator>,
     envelope: Box<dyn Envelope>,
+    filters: Vec<Filter>,
+    effects: Effects,
+    matrix: Matrix,
+    sample_rate: i32,
 }
 
 impl Instrument {
@@ -22,68 +63,273 @@ impl Instrument {
         T: Generator + 'static,
         U: Envelope + 'static,
     {
-        Self {
-            generator: Box::new(generator),
-            envelope: Box::new(envelope),
-        }
+        Self::new_boxed(Box::new(generator), Box::new(envelope))
     }
     pub fn new_boxed(generator: Box<dyn Generator>, envelope: Box<dyn Envelope>) -> Self {
         Self {
             generator,
             envelope,
+            filters: Vec::new(),
+            effects: Effects::default(),
+            matrix: Matrix::default(),
+            sample_rate: SAMPLE_RATE,
         }
     }
 
+    // Filters are applied in the order they were added
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    // Effects run on the enveloped sound, in the order they were added
+    pub fn with_effect(self, effect: impl Effect + 'static) -> Self {
+        self.with_effect_box(Box::new(effect))
+    }
+
+    pub fn with_effect_box(mut self, effect: Box<dyn Effect>) -> Self {
+        self.effects.push(effect);
+        self
+    }
+
+    pub fn with_modulation(mut self, source: Source, destination: Destination, depth: f64) -> Self {
+        self.matrix.route(source, destination, depth);
+        self
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: i32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+
     pub fn play(&self, bpm: f64, note: Note, volume: f64) -> Vec<f64> {
-        self.generator
-            .play(bpm, note)
-            .iter()
-            .enumerate()
-            .map(|(i, x)| {
-                let env = self
-                    .envelope
-                    .value_at(i as f64 / SAMPLE_RATE as f64, volume);
-                env * x
-            })
-            .collect()
+        self.render(bpm, note, volume, self.sample_rate)
+    }
+
+    pub fn render(&self, bpm: f64, note: Note, volume: f64, sample_rate: i32) -> Vec<f64> {
+        let mut voice = self.voice(bpm, note, volume).with_sample_rate(sample_rate);
+        let mut result = vec![0.0; voice.frames().unwrap_or_default()];
+        voice.process(&mut result);
+        result
+    }
+
+    pub fn play_stereo(&self, bpm: f64, note: Note, volume: f64) -> Stereo {
+        self.render_stereo(bpm, note, volume, 0.0, self.sample_rate)
+    }
+
+    pub fn render_stereo(
+        &self,
+        bpm: f64,
+        note: Note,
+        volume: f64,
+        pan: f64,
+        sample_rate: i32,
+    ) -> Stereo {
+        let mut voice = self.voice(bpm, note, volume).with_sample_rate(sample_rate);
+        let mut result = Stereo::new(voice.frames().unwrap_or_default());
+        voice.process_stereo(pan, &mut result.left, &mut result.right);
+        result
+    }
+
+    pub fn voice(&self, bpm: f64, note: Note, volume: f64) -> Voice<'_> {
+        self.note_on(note, volume).with_duration(note.secs(bpm))
+    }
+
+    pub fn note_on(&self, note: Note, volume: f64) -> Voice<'_> {
+        Voice::new(self, note, volume)
+    }
+
+    // Notes all start together, each lasting its own length
+    pub fn play_chord(&self, bpm: f64, notes: &[Note], volume: f64) -> Vec<f64> {
+        self.render_chord(bpm, notes, volume, self.sample_rate)
+    }
+
+    pub fn render_chord(
+        &self,
+        bpm: f64,
+        notes: &[Note],
+        volume: f64,
+        sample_rate: i32,
+    ) -> Vec<f64> {
+        let mut poly = self.poly().with_sample_rate(sample_rate);
+        for note in notes {
+            poly.note_on_for(*note, volume, note.secs(bpm));
+        }
+        let mut result = vec![0.0; poly.remaining().unwrap_or_default()];
+        poly.process(&mut result);
+        result
+    }
+
+    pub fn play_sequence(&self, bpm: f64, sequence: &Sequence, volume: f64) -> Vec<f64> {
+        self.render_sequence(bpm, sequence, volume, self.sample_rate)
+    }
+
+    pub fn render_sequence(
+        &self,
+        bpm: f64,
+        sequence: &Sequence,
+        volume: f64,
+        sample_rate: i32,
+    ) -> Vec<f64> {
+        let mut poly = self.poly().with_sample_rate(sample_rate);
+        sequence.render(&mut poly, &Tempo::new(bpm), volume)
+    }
+
+    pub fn poly(&self) -> Poly<'_> {
+        Poly::new(self)
     }
 }
 
-#[derive(Default)]
 pub struct Rack {
-    instruments: Vec<(Instrument, f64)>,
+    channels: Vec<Channel>,
+    groups: Vec<(String, Bus)>,
+    returns: Vec<(String, Bus)>,
+    effects: Effects,
+    master: Master,
+    levels: Cell<Levels>,
+    sample_rate: i32,
+}
+
+impl Default for Rack {
+    fn default() -> Self {
+        Self {
+            channels: Vec::new(),
+            groups: Vec::new(),
+            returns: Vec::new(),
+            effects: Effects::default(),
+            master: Master::default(),
+            levels: Cell::default(),
+            sample_rate: SAMPLE_RATE,
+        }
+    }
 }
 
 impl Rack {
+    pub fn with_sample_rate(mut self, sample_rate: i32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn with_master(mut self, master: Master) -> Self {
+        self.master = master;
+        self
+    }
+
+    pub fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+
+    // Peak and RMS of whatever was played last, as it left the master
+    pub fn levels(&self) -> Levels {
+        self.levels.get()
+    }
+
     pub fn add(&mut self, i: Instrument) {
-        self.instruments.push((i, 1.0));
+        self.add_channel(Channel::new(i));
     }
 
     pub fn add_with_volume(&mut self, i: Instrument, volume: f64) {
-        self.instruments.push((i, volume));
+        self.add_channel(Channel::new(i).with_volume(volume));
+    }
+
+    // Pan goes from -1.0 (left) to 1.0 (right) and only shows in stereo
+    pub fn add_with_pan(&mut self, i: Instrument, volume: f64, pan: f64) {
+        self.add_channel(Channel::new(i).with_volume(volume).with_pan(pan));
+    }
+
+    pub fn add_channel(&mut self, channel: Channel) {
+        self.channels.push(channel);
+    }
+
+    // Channels with this group name mix into the bus instead of the main mix
+    pub fn add_group(&mut self, name: &str, bus: Bus) {
+        self.groups.push((name.to_string(), bus));
+    }
+
+    // Channel sends with this name feed the bus, which then joins the main mix
+    pub fn add_return(&mut self, name: &str, bus: Bus) {
+        self.returns.push((name.to_string(), bus));
+    }
+
+    // Effects run on the summed instruments, in the order they were added
+    pub fn add_effect(&mut self, effect: impl Effect + 'static) {
+        self.effects.push(Box::new(effect));
     }
 
     pub fn play(&self, bpm: f64, note: Note, volume: f64) -> Vec<f64> {
-        let duration = note.secs(bpm);
-        let sample_duration = (SAMPLE_RATE as f64 * duration).floor() as usize;
-        /*
-        let instrs = self.instruments.len();
-        let mut samples = vec![vec![0.0; instrs]; sample_duration];
-        self.instruments.iter().enumerate().for_each(|(i, x)| {
-            x.0.play(bpm, note, volume * x.1)
-                .iter()
-                .enumerate()
-                .for_each(|(s, &y)| samples[s][i] = y);
+        let mut mix = self.stream(bpm, note, volume);
+        let mut result = vec![0.0; mix.frames().unwrap_or_default()];
+        mix.process(&mut result);
+        self.levels.set(self.master.normalize(&mut result));
+        result
+    }
+
+    pub fn play_chord(&self, bpm: f64, notes: &[Note], volume: f64) -> Vec<f64> {
+        let mut result = self.mixdown(|channel| {
+            let volume = volume * channel.volume;
+            channel
+                .instrument
+                .render_chord(bpm, notes, volume, self.sample_rate)
         });
-        samples.iter().map(|x| x.iter().sum()).collect()
-        */
-
-        let mut result = vec![0.0; sample_duration];
-        for instr in &self.instruments {
-            for (i, val) in instr.0.play(bpm, note, volume * instr.1).iter().enumerate() {
-                result[i] += val;
-            }
-        }
+        let levels = self.master.process(&mut result, self.sample_rate);
+        self.levels.set(levels);
+        result
+    }
+
+    pub fn play_sequence(&self, bpm: f64, sequence: &Sequence, volume: f64) -> Vec<f64> {
+        let mut result = self.mixdown(|channel| {
+            let volume = volume * channel.volume;
+            channel
+                .instrument
+                .render_sequence(bpm, sequence, volume, self.sample_rate)
+        });
+        let levels = self.master.process(&mut result, self.sample_rate);
+        self.levels.set(levels);
+        result
+    }
+
+    pub fn play_stereo(&self, bpm: f64, note: Note, volume: f64) -> Stereo {
+        let mut result = self.mixdown(|channel| {
+            let volume = volume * channel.volume;
+            channel
+                .instrument
+                .render_stereo(bpm, note, volume, channel.pan, self.sample_rate)
+        });
+        let levels = self.master.process_stereo(&mut result, self.sample_rate);
+        self.levels.set(levels);
         result
     }
+
+    // Renders every channel in full, then routes them all in one go with
+    // room for the bus effect tails.
+    fn mixdown<B, F>(&self, render: F) -> B
+    where
+        B: Buffer,
+        F: Fn(&Channel) -> B,
+    {
+        let mut parts: Vec<B> = self.channels.iter().map(render).collect();
+        let sound = parts.iter().map(|x| x.frames()).max().unwrap_or_default();
+        let frames = sound + samples(self.tail(), self.sample_rate);
+        parts.iter_mut().for_each(|x| x.resize(frames));
+        Router::new::<B>(self).process(&parts, frames)
+    }
+
+    // Longest a bus keeps ringing once the channels have gone quiet
+    fn tail(&self) -> f64 {
+        let buses = self.groups.iter().chain(self.returns.iter());
+        let bus = buses.map(|(_, x)| x.tail()).fold(0.0, f64::max);
+        bus + self.effects.tail()
+    }
+
+    pub fn stream(&self, bpm: f64, note: Note, volume: f64) -> Mix<'_> {
+        self.note_on(note, volume).with_duration(note.secs(bpm))
+    }
+
+    pub fn note_on(&self, note: Note, volume: f64) -> Mix<'_> {
+        Mix::new(self, note, volume)
+    }
 }
@@ -0,0 +1,270 @@
+use crate::effect::{Effect, Limiter, Shape};
+use crate::stereo::Stereo;
+
+// Peak and RMS levels of a rendered sound, linear
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub peak: f64,
+    pub rms: f64,
+}
+
+impl Levels {
+    pub fn of(sound: &[f64]) -> Self {
+        Self::of_channels(&[sound])
+    }
+
+    pub fn of_stereo(sound: &Stereo) -> Self {
+        Self::of_channels(&[&sound.left, &sound.right])
+    }
+
+    fn of_channels(channels: &[&[f64]]) -> Self {
+        let count: usize = channels.iter().map(|x| x.len()).sum();
+        if count == 0 {
+            return Self::default();
+        }
+        let samples = channels.iter().flat_map(|x| x.iter());
+        let (peak, power) = samples.fold((0.0, 0.0), |(peak, power): (f64, f64), x| {
+            (peak.max(x.abs()), power + x * x)
+        });
+        Self {
+            peak,
+            rms: (power / count as f64).sqrt(),
+        }
+    }
+
+    pub fn peak_db(&self) -> f64 {
+        20.0 * self.peak.log10()
+    }
+
+    pub fn rms_db(&self) -> f64 {
+        20.0 * self.rms.log10()
+    }
+
+    pub fn is_clipping(&self) -> bool {
+        self.peak > 1.0
+    }
+}
+
+// Last stage before the sound leaves a rack: limiter, then soft clipping,
+// then normalization, each only when asked for. Streams run the first two
+// block by block, but can't normalize as that takes the whole sound.
+#[derive(Default)]
+pub struct Master {
+    limiter: Option<Limiter>,
+    clip: bool,
+    normalize: Option<f64>,
+}
+
+impl Master {
+    pub fn with_limiter(mut self, limiter: Limiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    pub fn with_soft_clip(mut self) -> Self {
+        self.clip = true;
+        self
+    }
+
+    // Scales the whole sound so its peak lands on `peak`
+    pub fn with_normalize(mut self, peak: f64) -> Self {
+        self.normalize = Some(peak);
+        self
+    }
+
+    pub fn process(&self, sound: &mut Vec<f64>, sample_rate: i32) -> Levels {
+        self.limit(sound, sample_rate);
+        self.normalize(sound)
+    }
+
+    // Just the normalization, for sound a stream already limited and clipped
+    pub fn normalize(&self, sound: &mut [f64]) -> Levels {
+        let gain = self.gain(Levels::of(sound));
+        sound.iter_mut().for_each(|x| *x *= gain);
+        Levels::of(sound)
+    }
+
+    // Limiter and soft clip with state of their own, for one stream
+    pub(crate) fn stage(&self) -> Stage {
+        Stage {
+            limiter: self.limiter.clone(),
+            clip: self.clip,
+        }
+    }
+
+    // Both channels get the same gain at every stage, so the image stays put
+    pub fn process_stereo(&self, sound: &mut Stereo, sample_rate: i32) -> Levels {
+        let mut stage = self.stage();
+        let latency = stage.latency(sample_rate);
+        sound.resize(sound.len() + latency);
+        stage.process_stereo(&mut sound.left, &mut sound.right, sample_rate);
+        sound.left.drain(..latency);
+        sound.right.drain(..latency);
+        self.normalize_stereo(sound)
+    }
+
+    pub fn normalize_stereo(&self, sound: &mut Stereo) -> Levels {
+        let gain = self.gain(Levels::of_stereo(sound));
+        for channel in [&mut sound.left, &mut sound.right] {
+            channel.iter_mut().for_each(|x| *x *= gain);
+        }
+        Levels::of_stereo(sound)
+    }
+
+    // The limiter runs late by its look-ahead, which gets trimmed back off
+    fn limit(&self, sound: &mut Vec<f64>, sample_rate: i32) {
+        let mut stage = self.stage();
+        let latency = stage.latency(sample_rate);
+        sound.resize(sound.len() + latency, 0.0);
+        stage.process(sound, sample_rate);
+        sound.drain(..latency);
+    }
+
+    fn gain(&self, levels: Levels) -> f64 {
+        match self.normalize {
+            Some(peak) if levels.peak > 0.0 => peak / levels.peak,
+            _ => 1.0,
+        }
+    }
+}
+
+pub(crate) struct Stage {
+    limiter: Option<Limiter>,
+    clip: bool,
+}
+
+impl Stage {
+    // Frames the output runs behind the input
+    pub fn latency(&self, sample_rate: i32) -> usize {
+        self.limiter.as_ref().map_or(0, |x| x.latency(sample_rate))
+    }
+
+    pub fn process(&mut self, sound: &mut [f64], sample_rate: i32) {
+        if let Some(limiter) = &mut self.limiter {
+            limiter.process(sound, sample_rate);
+        }
+        if self.clip {
+            sound.iter_mut().for_each(|x| *x = Shape::Soft.apply(*x));
+        }
+    }
+
+    // Clipping scales both channels by what the louder one needs
+    pub fn process_stereo(&mut self, left: &mut [f64], right: &mut [f64], sample_rate: i32) {
+        if let Some(limiter) = &mut self.limiter {
+            limiter.process_stereo(left, right, sample_rate);
+        }
+        if self.clip {
+            for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                let peak = l.abs().max(r.abs());
+                if peak > 0.0 {
+                    let gain = Shape::Soft.apply(peak) / peak;
+                    *l *= gain;
+                    *r *= gain;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_of_square() {
+        let sound: Vec<f64> = (0..100)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect();
+        let levels = Levels::of(&sound);
+        assert_eq!(0.5, levels.peak);
+        assert!((0.5 - levels.rms).abs() < 1e-9);
+        assert!((-6.0206 - levels.peak_db()).abs() < 1e-3);
+        assert!(!levels.is_clipping());
+        assert_eq!(Levels::default(), Levels::of(&[]));
+    }
+
+    #[test]
+    fn stages_tame_loud_sound() {
+        let loud: Vec<f64> = (0..44100).map(|i| 3.0 * (i as f64 / 100.0).sin()).collect();
+
+        let mut sound = loud.clone();
+        let levels = Master::default().process(&mut sound, 44100);
+        assert_eq!(loud, sound);
+        assert!(levels.is_clipping());
+
+        let mut sound = loud.clone();
+        let levels = Master::default()
+            .with_normalize(0.5)
+            .process(&mut sound, 44100);
+        assert!((0.5 - levels.peak).abs() < 1e-9);
+
+        let mut sound = loud.clone();
+        let levels = Master::default()
+            .with_soft_clip()
+            .process(&mut sound, 44100);
+        assert!(levels.peak < 1.0 && levels.peak > 0.99);
+
+        let mut sound = loud.clone();
+        let master = Master::default().with_limiter(Limiter::new(0.8));
+        let levels = master.process(&mut sound, 44100);
+        assert_eq!(loud.len(), sound.len());
+        assert!(levels.peak <= 0.8 + 1e-9);
+        // No delay left after trimming the look-ahead
+        assert!(loud
+            .iter()
+            .zip(sound.iter())
+            .all(|(x, y)| x.abs() < 0.1 || x.signum() == y.signum()));
+    }
+
+    #[test]
+    fn stereo_channels_share_gain() {
+        let loud: Vec<f64> = (0..44100).map(|i| 3.0 * (i as f64 / 100.0).sin()).collect();
+        let quiet: Vec<f64> = loud.iter().map(|x| x * 0.1).collect();
+        let masters = [
+            Master::default().with_limiter(Limiter::new(0.8)),
+            Master::default().with_soft_clip(),
+        ];
+        for master in masters {
+            let mut sound = Stereo {
+                left: loud.clone(),
+                right: quiet.clone(),
+            };
+            let levels = master.process_stereo(&mut sound, 44100);
+            assert!(levels.peak < 1.0);
+            // The quiet side comes down with the loud one
+            assert!(sound
+                .left
+                .iter()
+                .zip(sound.right.iter())
+                .all(|(l, r)| (l * 0.1 - r).abs() < 1e-9));
+        }
+    }
+
+    #[test]
+    fn rack_runs_master_last() {
+        use crate::envelope::Fixed;
+        use crate::generator::detuned::Freq;
+        use crate::{Instrument, Rack};
+        use note::*;
+
+        let rack = || {
+            let mut rack = Rack::default();
+            rack.add(Instrument::new(Freq::square(13.0), Fixed {}));
+            rack.add(Instrument::new(Freq::square(-12.0), Fixed {}));
+            rack
+        };
+        let note = note![A: C4, 1 / 16];
+        assert!(Levels::of(&rack().play(90.0, note, 1.0)).is_clipping());
+
+        let master = Master::default()
+            .with_limiter(Limiter::new(0.9))
+            .with_normalize(0.9);
+        let rack = rack().with_master(master);
+        let levels = Levels::of(&rack.play(90.0, note, 1.0));
+        assert!((0.9 - levels.peak).abs() < 1e-9);
+        assert_eq!(levels, rack.levels());
+
+        let levels = Levels::of_stereo(&rack.play_stereo(90.0, note, 1.0));
+        assert_eq!(levels, rack.levels());
+    }
+}
@@ -0,0 +1,329 @@
+use crate::effect::{Effect, Effects};
+use crate::stereo::Stereo;
+use crate::{Instrument, Rack};
+
+// A rack strip: one instrument with its fader and pan, optionally routed
+// into a group instead of straight to the main mix, and sending some of
+// its (post-fader) signal to any number of returns.
+pub struct Channel {
+    pub(crate) instrument: Instrument,
+    pub(crate) volume: f64,
+    pub(crate) pan: f64,
+    group: Option<String>,
+    sends: Vec<(String, f64)>,
+}
+
+impl Channel {
+    pub fn new(instrument: Instrument) -> Self {
+        Self {
+            instrument,
+            volume: 1.0,
+            pan: 0.0,
+            group: None,
+            sends: Vec::new(),
+        }
+    }
+
+    pub fn with_volume(mut self, volume: f64) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    // -1.0 (left) to 1.0 (right), only heard in stereo
+    pub fn with_pan(mut self, pan: f64) -> Self {
+        self.pan = pan.clamp(-1.0, 1.0);
+        self
+    }
+
+    pub fn with_group(mut self, name: &str) -> Self {
+        self.group = Some(name.to_string());
+        self
+    }
+
+    pub fn with_send(mut self, name: &str, level: f64) -> Self {
+        self.sends.push((name.to_string(), level));
+        self
+    }
+}
+
+// Group or return bus: a volume and effects of its own
+pub struct Bus {
+    volume: f64,
+    effects: Effects,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            effects: Effects::default(),
+        }
+    }
+}
+
+impl Bus {
+    pub fn with_volume(mut self, volume: f64) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub fn with_effect(self, effect: impl Effect + 'static) -> Self {
+        self.with_effect_box(Box::new(effect))
+    }
+
+    pub fn with_effect_box(mut self, effect: Box<dyn Effect>) -> Self {
+        self.effects.push(effect);
+        self
+    }
+
+    pub(crate) fn tail(&self) -> f64 {
+        self.effects.tail()
+    }
+}
+
+// Mono or stereo block going through the router
+pub(crate) trait Buffer: Sized {
+    const LANES: usize;
+
+    fn silence(frames: usize) -> Self;
+    fn frames(&self) -> usize;
+    fn resize(&mut self, frames: usize);
+    fn lanes(&self) -> Vec<&[f64]>;
+    fn lanes_mut(&mut self) -> Vec<&mut [f64]>;
+
+    fn mix(&mut self, other: &Self, gain: f64) {
+        for (x, y) in self.lanes_mut().into_iter().zip(other.lanes()) {
+            x.iter_mut().zip(y.iter()).for_each(|(x, y)| *x += y * gain);
+        }
+    }
+}
+
+impl Buffer for Vec<f64> {
+    const LANES: usize = 1;
+
+    fn silence(frames: usize) -> Self {
+        vec![0.0; frames]
+    }
+
+    fn frames(&self) -> usize {
+        self.len()
+    }
+
+    fn resize(&mut self, frames: usize) {
+        Vec::resize(self, frames, 0.0);
+    }
+
+    fn lanes(&self) -> Vec<&[f64]> {
+        vec![self]
+    }
+
+    fn lanes_mut(&mut self) -> Vec<&mut [f64]> {
+        vec![self]
+    }
+}
+
+impl Buffer for Stereo {
+    const LANES: usize = 2;
+
+    fn silence(frames: usize) -> Self {
+        Stereo::new(frames)
+    }
+
+    fn frames(&self) -> usize {
+        self.len()
+    }
+
+    fn resize(&mut self, frames: usize) {
+        Stereo::resize(self, frames);
+    }
+
+    fn lanes(&self) -> Vec<&[f64]> {
+        vec![&self.left, &self.right]
+    }
+
+    fn lanes_mut(&mut self) -> Vec<&mut [f64]> {
+        vec![&mut self.left, &mut self.right]
+    }
+}
+
+// Effect state for every bus of a rack, one copy per lane
+pub(crate) struct Router<'a> {
+    rack: &'a Rack,
+    main: Vec<Effects>,
+    groups: Vec<Vec<Effects>>,
+    returns: Vec<Vec<Effects>>,
+}
+
+impl<'a> Router<'a> {
+    pub(crate) fn new<B: Buffer>(rack: &'a Rack) -> Self {
+        let fresh = |x: &Effects| (0..B::LANES).map(|_| x.fresh()).collect();
+        Self {
+            rack,
+            main: fresh(&rack.effects),
+            groups: rack.groups.iter().map(|(_, x)| fresh(&x.effects)).collect(),
+            returns: rack
+                .returns
+                .iter()
+                .map(|(_, x)| fresh(&x.effects))
+                .collect(),
+        }
+    }
+
+    // Routes one block from every channel (in rack order) down to the main
+    // mix: channels into groups or main, sends into returns, then groups
+    // and returns into main, then the main effects.
+    pub(crate) fn process<B: Buffer>(&mut self, parts: &[B], frames: usize) -> B {
+        let rack = self.rack;
+        let mut main = B::silence(frames);
+        let mut groups: Vec<B> = rack.groups.iter().map(|_| B::silence(frames)).collect();
+        let mut returns: Vec<B> = rack.returns.iter().map(|_| B::silence(frames)).collect();
+        let find = |buses: &[(String, Bus)], name: &str| buses.iter().position(|x| x.0 == name);
+
+        for (channel, part) in rack.channels.iter().zip(parts) {
+            let group = channel.group.as_deref().and_then(|x| find(&rack.groups, x));
+            match group {
+                Some(i) => groups[i].mix(part, 1.0),
+                None => main.mix(part, 1.0),
+            }
+            for (name, level) in channel.sends.iter() {
+                if let Some(i) = find(&rack.returns, name) {
+                    returns[i].mix(part, *level);
+                }
+            }
+        }
+
+        let buses = rack
+            .groups
+            .iter()
+            .zip(groups.iter_mut().zip(self.groups.iter_mut()));
+        let aux = rack
+            .returns
+            .iter()
+            .zip(returns.iter_mut().zip(self.returns.iter_mut()));
+        for ((_, bus), (sound, effects)) in buses.chain(aux) {
+            apply(sound, effects, rack.sample_rate);
+            main.mix(sound, bus.volume);
+        }
+        apply(&mut main, &mut self.main, rack.sample_rate);
+        main
+    }
+}
+
+fn apply<B: Buffer>(sound: &mut B, effects: &mut [Effects], sample_rate: i32) {
+    for (lane, effects) in sound.lanes_mut().into_iter().zip(effects.iter_mut()) {
+        effects.process(lane, sample_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect::Limiter;
+    use crate::effect::{Delay, Distortion, Reverb, Shape};
+    use crate::envelope::Fixed;
+    use crate::generator::simple::Simple;
+    use crate::master::{Levels, Master};
+    use crate::testing::{close, drain, synth};
+    use note::*;
+
+    #[test]
+    fn group_applies_volume_and_effects() {
+        let note = note![A: C4, 1 / 16];
+        let mut rack = Rack::default();
+        rack.add_group(
+            "drums",
+            Bus::default()
+                .with_volume(0.5)
+                .with_effect(Distortion::new(Shape::Hard, 2.0)),
+        );
+        rack.add_channel(Channel::new(synth()).with_group("drums"));
+        rack.add_channel(Channel::new(synth()).with_group("drums"));
+
+        let mut expected = synth().play(90.0, note, 2.0);
+        expected
+            .iter_mut()
+            .for_each(|x| *x = 0.5 * Shape::Hard.apply(2.0 * *x));
+        assert!(close(&expected, &rack.play(90.0, note, 1.0)));
+
+        // Unknown groups go straight to the main mix
+        let mut rack = Rack::default();
+        rack.add_channel(Channel::new(synth()).with_group("nowhere"));
+        assert!(close(
+            &synth().play(90.0, note, 1.0),
+            &rack.play(90.0, note, 1.0)
+        ));
+    }
+
+    #[test]
+    fn sends_share_one_return() {
+        let note = note![A: C4, 1 / 16];
+        let mut rack = Rack::default();
+        rack.add_return(
+            "echo",
+            Bus::default().with_effect(Delay::new(0.1, 0.0).with_mix(1.0)),
+        );
+        rack.add_channel(Channel::new(synth()).with_send("echo", 0.5));
+        rack.add_channel(
+            Channel::new(synth())
+                .with_volume(0.5)
+                .with_send("echo", 1.0),
+        );
+
+        let dry = synth().play(90.0, note, 1.0);
+        let sound = rack.play(90.0, note, 1.0);
+        let offset = crate::samples(0.1, 44100);
+        assert_eq!(
+            crate::samples(note.secs(90.0) + 0.05 + 0.1, 44100),
+            sound.len()
+        );
+        for (i, x) in sound.iter().enumerate() {
+            let direct = dry.get(i).unwrap_or(&0.0) * 1.5;
+            let echo = i
+                .checked_sub(offset)
+                .and_then(|j| dry.get(j))
+                .unwrap_or(&0.0);
+            assert!((direct + echo - x).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn streaming_and_stereo_follow_routing() {
+        let note = note![A: C4, 1 / 16];
+        let rack = |master: Master| {
+            let mut rack = Rack::default().with_master(master);
+            rack.add_group("pads", Bus::default().with_volume(0.5));
+            rack.add_return("hall", Bus::default().with_effect(Reverb::new(0.5, 0.5)));
+            rack.add_channel(
+                Channel::new(synth())
+                    .with_group("pads")
+                    .with_send("hall", 0.3),
+            );
+            let square = Instrument::new(Simple::square(), Fixed {});
+            rack.add_channel(Channel::new(square).with_pan(1.0).with_send("hall", 0.3));
+            rack
+        };
+        let master = || {
+            Master::default()
+                .with_limiter(Limiter::new(0.5))
+                .with_soft_clip()
+        };
+
+        // Streaming runs the master block by block, same as a whole render
+        let mut whole = rack(Master::default()).play(90.0, note, 1.0);
+        master().process(&mut whole, 44100);
+        let rack = rack(master());
+        let streamed = drain(&mut rack.stream(90.0, note, 1.0), 256);
+        assert!(close(&whole, &streamed));
+        assert!(close(&whole, &rack.play(90.0, note, 1.0)));
+        assert!(whole.iter().all(|x| x.abs() <= 0.5));
+
+        let stereo = rack.play_stereo(90.0, note, 1.0);
+        assert_eq!(whole.len(), stereo.len());
+        assert!(Levels::of_stereo(&stereo).peak <= 0.5);
+        // Only the grouped sine is on the left, and the shared reverb keeps
+        // ringing well after both notes are over
+        assert!(stereo.left.iter().any(|x| x.abs() > 0.01));
+        let tail = crate::samples(note.secs(90.0) + 0.1, 44100);
+        assert!(stereo.left[tail..].iter().any(|x| x.abs() > 1e-4));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::envelope::Envelope;
+use crate::generator::State;
+use crate::Signal;
+use std::slice::Iter;
+
+pub enum Source {
+    Lfo(Box<dyn Signal>),
+    Envelope(Box<dyn Envelope>),
+    // Volume the note was played at
+    Velocity,
+    // Octaves away from A4, for key tracking
+    Pitch,
+}
+
+impl Source {
+    pub fn lfo(what: impl Signal + 'static) -> Self {
+        Self::Lfo(Box::new(what))
+    }
+
+    pub fn envelope(what: impl Envelope + 'static) -> Self {
+        Self::Envelope(Box::new(what))
+    }
+
+    pub fn value_at(&self, ctx: &Context) -> f64 {
+        match self {
+            Self::Lfo(x) => x.value_at(ctx.t, ctx.frequency, ctx.sample_rate),
+            Self::Envelope(x) => x.released_at(ctx.t, ctx.off, 1.0),
+            Self::Velocity => ctx.velocity,
+            Self::Pitch if ctx.frequency > 0.0 => (ctx.frequency / 440.0).log2(),
+            Self::Pitch => 0.0,
+        }
+    }
+}
+
+// Units of depth depend on the destination: semitones for pitch, gain
+// offset for amplitude, octaves for filter cutoff, duty cycle for pulse
+// width, -1.0 (left) - 1.0 (right) for pan and a factor of the generator's
+// own detune for detune.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Pitch,
+    Amplitude,
+    Cutoff,
+    PulseWidth,
+    Pan,
+    Detune,
+}
+
+// Where in the note a modulation is evaluated
+#[derive(Copy, Clone, Debug)]
+pub struct Context {
+    pub t: f64,
+    pub off: f64,
+    pub frequency: f64,
+    pub velocity: f64,
+    pub sample_rate: i32,
+}
+
+// Modulation handed down to generators, per sample
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Params {
+    pub pulse_width: f64,
+    pub detune: f64,
+}
+
+#[derive(Default)]
+pub struct Matrix {
+    routes: Vec<(Source, Destination, f64)>,
+}
+
+impl Matrix {
+    pub fn route(&mut self, source: Source, destination: Destination, depth: f64) -> &mut Self {
+        self.routes.push((source, destination, depth));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn targets(&self, destination: Destination) -> bool {
+        self.routes.iter().any(|x| x.1 == destination)
+    }
+
+    pub fn value_at(&self, destination: Destination, ctx: &Context) -> f64 {
+        self.routes
+            .iter()
+            .filter(|x| x.1 == destination)
+            .map(|(source, _, depth)| depth * source.value_at(ctx))
+            .sum()
+    }
+
+    pub fn params_at(&self, ctx: &Context) -> Params {
+        Params {
+            pulse_width: self.value_at(Destination::PulseWidth, ctx),
+            detune: self.value_at(Destination::Detune, ctx),
+        }
+    }
+
+    // LFO state, kept per voice just like the generator's; see `Signal::save`
+    pub fn save(&self, state: &mut Vec<State>) {
+        self.lfos().for_each(|x| x.save(state));
+    }
+
+    pub fn load(&self, state: &mut Iter<State>) {
+        self.lfos().for_each(|x| x.load(state));
+    }
+
+    fn lfos(&self) -> impl Iterator<Item = &dyn Signal> {
+        self.routes
+            .iter()
+            .filter_map(|(source, _, _)| match source {
+                Source::Lfo(x) => Some(x.as_ref()),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{Fixed, ASR, RAR};
+    use crate::generator::{detuned::Freq, pulse::Pulse, simple::Simple};
+    use crate::oscillator::{Oscillator, Phasor};
+    use crate::testing::{assert_chord_is_sum, crossings};
+    use crate::voice::Stream;
+    use crate::Instrument;
+    use note::*;
+
+    fn ctx(t: f64) -> Context {
+        Context {
+            t,
+            off: f64::INFINITY,
+            frequency: 880.0,
+            velocity: 0.5,
+            sample_rate: 44100,
+        }
+    }
+
+    fn render(instrument: &Instrument) -> Vec<f64> {
+        let mut voice = instrument.note_on(note![A: C4, 1 / 4], 1.0);
+        let mut sound = vec![0.0; 44100];
+        voice.process(&mut sound);
+        sound
+    }
+
+    #[test]
+    fn routes_sum_per_destination() {
+        let mut matrix = Matrix::default();
+        matrix
+            .route(Source::Velocity, Destination::Amplitude, 2.0)
+            .route(Source::Pitch, Destination::Amplitude, 0.5)
+            .route(
+                Source::envelope(ASR::new(1.0, 0.0, 0.0)),
+                Destination::Cutoff,
+                3.0,
+            );
+
+        assert_eq!(1.5, matrix.value_at(Destination::Amplitude, &ctx(0.0)));
+        assert_eq!(1.5, matrix.value_at(Destination::Cutoff, &ctx(0.5)));
+        assert_eq!(0.0, matrix.value_at(Destination::Pan, &ctx(0.5)));
+        assert!(matrix.targets(Destination::Cutoff));
+        assert!(!matrix.targets(Destination::Pitch));
+    }
+
+    #[test]
+    fn pitch_and_amplitude() {
+        let plain = Instrument::new(Simple::default(), Fixed {});
+        let octave = Instrument::new(Simple::default(), Fixed {})
+            .with_modulation(Source::envelope(Fixed {}), Destination::Pitch, 12.0)
+            .with_modulation(Source::Velocity, Destination::Amplitude, -0.5);
+
+        let plain = render(&plain);
+        let octave = render(&octave);
+        assert_eq!(2 * crossings(&plain), crossings(&octave));
+        let peak = octave.iter().fold(0.0, |x: f64, y| x.max(y.abs()));
+        assert!((peak - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn pulse_width_and_detune() {
+        let pwm = Instrument::new(Pulse::new(0.5), Fixed {}).with_modulation(
+            Source::envelope(Fixed {}),
+            Destination::PulseWidth,
+            -0.25,
+        );
+        let high = render(&pwm).iter().filter(|&&x| x > 0.0).count();
+        assert!((high as f64 / 44100.0 - 0.25).abs() < 0.01);
+
+        let plain = render(&Instrument::new(Freq::new(Oscillator::Sine, 2.0), Fixed {}));
+        let detuned = Instrument::new(Freq::new(Oscillator::Sine, 1.0), Fixed {}).with_modulation(
+            Source::envelope(Fixed {}),
+            Destination::Detune,
+            1.0,
+        );
+        for (a, b) in plain.iter().zip(render(&detuned).iter()) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn overlapping_voices_keep_their_own_lfos() {
+        // Phasors run at the note's frequency, so the two voices would drag
+        // a shared one back and forth
+        let synth = Instrument::new(Simple::default(), RAR::new(0.01, 0.05))
+            .with_modulation(
+                Source::lfo(Phasor::new(Oscillator::Saw)),
+                Destination::Amplitude,
+                0.5,
+            )
+            .with_modulation(
+                Source::lfo(Phasor::new(Oscillator::Sine)),
+                Destination::Pitch,
+                0.5,
+            );
+        assert_chord_is_sum(&synth, &[note![A: C4, 1 / 8], note![E: C4, 1 / 16]], 1.0);
+    }
+}
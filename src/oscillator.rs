@@ -1,4 +1,9 @@
+use std::cell::Cell;
 use std::f64::consts::PI;
+use std::slice::Iter;
+
+use crate::generator::State;
+use crate::Signal;
 
 #[cfg(feature = "wasm")]
 use serde::{Deserialize, Serialize};
@@ -13,7 +18,16 @@ pub enum Oscillator {
     Square,
     Triangle,
     Saw,
+    BlSquare,
+    BlTriangle,
+    BlSaw,
+    // Fixed at `PULSE_WIDTH`; `generator::pulse::Pulse` takes any width
+    Pulse,
 }
+
+// Duty cycle used by `Oscillator::Pulse` when the width isn't given explicitly.
+pub const PULSE_WIDTH: f64 = 0.25;
+
 impl Oscillator {
     pub fn get(&self, frequency: f64) -> Osc {
         match self {
@@ -21,8 +35,55 @@ impl Oscillator {
             Self::Square => Osc::Square(frequency),
             Self::Triangle => Osc::Triangle(frequency),
             Self::Saw => Osc::Saw(frequency),
+            Self::BlSquare => Osc::BlSquare(frequency),
+            Self::BlTriangle => Osc::BlTriangle(frequency),
+            Self::BlSaw => Osc::BlSaw(frequency),
+            Self::Pulse => Osc::Pulse(frequency, PULSE_WIDTH),
         }
     }
+
+    // Shape value at a phase within the cycle (0.0 - 1.0), where `dt` is
+    // the phase increment per sample.
+    pub fn at_phase(&self, phase: f64, dt: f64) -> f64 {
+        match self {
+            Self::Sine => (phase * 2.0 * PI).sin(),
+            Self::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Self::Triangle => (2.0 / PI) * (phase * 2.0 * PI).sin().asin(),
+            Self::Saw => 2.0 * phase - 1.0,
+            // Band-limited shapes smooth out their discontinuities over the
+            // sampling step (PolyBLEP / PolyBLAMP).
+            Self::BlSquare => {
+                Self::Square.at_phase(phase, dt) + 2.0 * blep(phase, dt)
+                    - 2.0 * blep((phase + 0.5).fract(), dt)
+            }
+            Self::BlTriangle => {
+                Self::Triangle.at_phase(phase, dt) - 8.0 * dt * blamp((phase + 0.75).fract(), dt)
+                    + 8.0 * dt * blamp((phase + 0.25).fract(), dt)
+            }
+            Self::BlSaw => Self::Saw.at_phase(phase, dt) - 2.0 * blep(phase, dt),
+            Self::Pulse => pulse_at(phase, PULSE_WIDTH, dt),
+        }
+    }
+}
+
+// Band-limited pulse with the given duty cycle, clamped so that both edges
+// stay at least a sample apart.
+pub fn pulse_at(phase: f64, width: f64, dt: f64) -> f64 {
+    let width = width.clamp(dt, 1.0 - dt);
+    let naive = if phase < width { 1.0 } else { -1.0 };
+    naive + 2.0 * blep(phase, dt) - 2.0 * blep((phase - width).rem_euclid(1.0), dt)
+}
+
+impl Signal for Oscillator {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        self.get(frequency).sample(t, sample_rate)
+    }
 }
 
 impl From<i32> for Oscillator {
@@ -31,6 +92,10 @@ impl From<i32> for Oscillator {
             1 => Self::Square,
             2 => Self::Triangle,
             3 => Self::Saw,
+            4 => Self::BlSquare,
+            5 => Self::BlTriangle,
+            6 => Self::BlSaw,
+            7 => Self::Pulse,
             _ => Self::Sine,
         }
     }
@@ -42,6 +107,10 @@ pub enum Osc {
     Square(f64),
     Triangle(f64),
     Saw(f64),
+    BlSquare(f64),
+    BlTriangle(f64),
+    BlSaw(f64),
+    Pulse(f64, f64),
 }
 
 impl Osc {
@@ -56,9 +125,241 @@ impl Osc {
                 }
             }
             Self::Triangle(frequency) => (2.0 / PI) * (frequency * t * 2.0 * PI).sin().asin(),
-            Self::Saw(frequency) => {
+            Self::Saw(frequency) | Self::BlSaw(frequency) => {
                 (2.0 / PI) * (frequency * PI * (t % (1.0 / frequency)) - (PI / 2.0))
             }
+            Self::BlSquare(frequency) => Self::Square(*frequency).at(t),
+            Self::BlTriangle(frequency) => Self::Triangle(*frequency).at(t),
+            Self::Pulse(frequency, width) => {
+                if (frequency * t).rem_euclid(1.0) < *width {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        }
+    }
+
+    // Band-limited shapes (pulse included) need to know the sampling step to
+    // smooth out their discontinuities, the rest are sampled as-is.
+    pub fn sample(&self, t: f64, sample_rate: i32) -> f64 {
+        match self {
+            Self::BlSquare(frequency) => {
+                phase_step(*frequency, t, sample_rate, Oscillator::BlSquare)
+            }
+            Self::BlTriangle(frequency) => {
+                phase_step(*frequency, t, sample_rate, Oscillator::BlTriangle)
+            }
+            Self::BlSaw(frequency) => phase_step(*frequency, t, sample_rate, Oscillator::BlSaw),
+            Self::Pulse(frequency, width) => {
+                let phase = (frequency * t).rem_euclid(1.0);
+                let dt = (frequency / sample_rate as f64).min(0.5);
+                pulse_at(phase, *width, dt)
+            }
+            _ => self.at(t),
+        }
+    }
+}
+
+fn phase_step(frequency: f64, t: f64, sample_rate: i32, shape: Oscillator) -> f64 {
+    let phase = (frequency * t).rem_euclid(1.0);
+    let dt = (frequency / sample_rate as f64).min(0.5);
+    shape.at_phase(phase, dt)
+}
+
+// Stateful oscillator: keeps its running phase and advances it by the
+// current frequency, so pitch can change mid-note without phase jumps.
+// Starts over whenever time goes backwards (i.e. a new note is played), or
+// when a voice hands it a fresh state.
+#[derive(Debug)]
+pub struct Phasor {
+    shape: Oscillator,
+    phase: Cell<f64>,
+    last: Cell<Option<f64>>,
+}
+
+impl Phasor {
+    pub fn new(shape: Oscillator) -> Self {
+        Self {
+            shape,
+            phase: Cell::new(0.0),
+            last: Cell::new(None),
+        }
+    }
+
+    pub fn advance(&self, t: f64, frequency: f64) -> f64 {
+        let phase = match self.last.get() {
+            Some(last) if t >= last => self.phase.get() + frequency * (t - last),
+            _ => frequency * t,
+        };
+        let phase = phase.rem_euclid(1.0);
+        self.phase.set(phase);
+        self.last.set(Some(t));
+        phase
+    }
+}
+
+impl Signal for Phasor {
+    fn value_at(&self, t: f64, frequency: f64, sample_rate: i32) -> f64 {
+        let phase = self.advance(t, frequency);
+        let dt = (frequency.abs() / sample_rate as f64).min(0.5);
+        self.shape.at_phase(phase, dt)
+    }
+
+    fn save(&self, state: &mut Vec<State>) {
+        state.push(State::Clock(self.phase.get(), self.last.get()));
+    }
+
+    fn load(&self, state: &mut Iter<State>) {
+        let (phase, last) = match state.next() {
+            Some(&State::Clock(phase, last)) => (phase, last),
+            _ => (0.0, None),
+        };
+        self.phase.set(phase);
+        self.last.set(last);
+    }
+}
+
+// Residual of a unit step at phase 0, spread over one sample either side.
+fn blep(phase: f64, dt: f64) -> f64 {
+    if phase < dt {
+        let x = phase / dt;
+        x - x * x / 2.0 - 0.5
+    } else if phase > 1.0 - dt {
+        let x = (phase - 1.0) / dt;
+        x * x / 2.0 + x + 0.5
+    } else {
+        0.0
+    }
+}
+
+// Integrated BLEP: residual of a unit change in slope at phase 0.
+fn blamp(phase: f64, dt: f64) -> f64 {
+    if phase < dt {
+        let x = 1.0 - phase / dt;
+        x * x * x / 6.0
+    } else if phase > 1.0 - dt {
+        let x = (phase - 1.0) / dt + 1.0;
+        x * x * x / 6.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: i32 = 44100;
+    const LENGTH: usize = 4410;
+    const FREQUENCY: f64 = 2090.0;
+
+    // Share of spectral energy that falls outside of the harmonic series,
+    // which is where the foldover ends up.
+    fn alias_ratio(osc: Osc) -> f64 {
+        let samples: Vec<f64> = (0..LENGTH)
+            .map(|i| osc.sample(i as f64 / RATE as f64, RATE))
+            .collect();
+        let bin_width = RATE as f64 / LENGTH as f64;
+        let fundamental = (FREQUENCY / bin_width) as usize;
+
+        let mut harmonic = 0.0;
+        let mut alias = 0.0;
+        for bin in 1..LENGTH / 2 {
+            let (re, im) = samples.iter().enumerate().fold((0.0, 0.0), |acc, (i, x)| {
+                let angle = 2.0 * PI * ((bin * i) % LENGTH) as f64 / LENGTH as f64;
+                (acc.0 + x * angle.cos(), acc.1 - x * angle.sin())
+            });
+            let power = re * re + im * im;
+            if bin % fundamental == 0 {
+                harmonic += power;
+            } else {
+                alias += power;
+            }
+        }
+        alias / (harmonic + alias)
+    }
+
+    #[test]
+    fn band_limited_square_aliases_less() {
+        let naive = alias_ratio(Osc::Square(FREQUENCY));
+        let limited = alias_ratio(Osc::BlSquare(FREQUENCY));
+        assert!(limited * 4.0 < naive, "{} vs {}", limited, naive);
+    }
+
+    #[test]
+    fn band_limited_saw_aliases_less() {
+        let naive = alias_ratio(Osc::Saw(FREQUENCY));
+        let limited = alias_ratio(Osc::BlSaw(FREQUENCY));
+        assert!(limited * 4.0 < naive, "{} vs {}", limited, naive);
+    }
+
+    #[test]
+    fn band_limited_triangle_aliases_less() {
+        let naive = alias_ratio(Osc::Triangle(FREQUENCY));
+        let limited = alias_ratio(Osc::BlTriangle(FREQUENCY));
+        assert!(limited * 4.0 < naive, "{} vs {}", limited, naive);
+    }
+
+    #[test]
+    fn phasor_matches_stateless_at_fixed_pitch() {
+        let phasor = Phasor::new(Oscillator::Sine);
+        for i in 0..RATE as usize {
+            let t = i as f64 / RATE as f64;
+            let expected = Osc::Sine(440.0).sample(t, RATE);
+            assert!((phasor.value_at(t, 440.0, RATE) - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn phasor_is_continuous_across_pitch_change() {
+        let phasor = Phasor::new(Oscillator::Sine);
+        let mut phased = Vec::new();
+        let mut stateless = Vec::new();
+        for i in 0..RATE as usize {
+            let t = i as f64 / RATE as f64;
+            let frequency = if i < RATE as usize / 3 { 440.0 } else { 660.0 };
+            phased.push(phasor.value_at(t, frequency, RATE));
+            stateless.push(Oscillator::Sine.value_at(t, frequency, RATE));
+        }
+
+        // Largest step a 660Hz sine can make in one sample
+        let limit = 2.0 * PI * 660.0 / RATE as f64;
+        let jump = |x: &Vec<f64>| {
+            x.windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .fold(0.0, f64::max)
+        };
+        assert!(jump(&phased) <= limit);
+        assert!(jump(&stateless) > limit);
+    }
+
+    #[test]
+    fn phasor_restarts_with_new_note() {
+        let phasor = Phasor::new(Oscillator::Saw);
+        let first: Vec<f64> = (0..100)
+            .map(|i| phasor.value_at(i as f64 / RATE as f64, 220.0, RATE))
+            .collect();
+        let second: Vec<f64> = (0..100)
+            .map(|i| phasor.value_at(i as f64 / RATE as f64, 220.0, RATE))
+            .collect();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn band_limited_follows_naive_shape() {
+        for (naive, limited) in [
+            (Osc::Square(110.0), Osc::BlSquare(110.0)),
+            (Osc::Saw(110.0), Osc::BlSaw(110.0)),
+            (Osc::Triangle(110.0), Osc::BlTriangle(110.0)),
+        ] {
+            let diff: f64 = (0..RATE as usize)
+                .map(|i| {
+                    let t = i as f64 / RATE as f64;
+                    (naive.sample(t, RATE) - limited.sample(t, RATE)).abs()
+                })
+                .sum();
+            assert!(diff / (RATE as f64) < 0.02, "{:?}: {}", limited, diff);
         }
     }
 }
@@ -0,0 +1,371 @@
+use crate::effect::Effects;
+use crate::stereo::Stereo;
+use crate::voice::{Stream, Voice};
+use crate::Instrument;
+use note::Note;
+
+// Which voice makes room for a new one once the limit is reached. The
+// stolen voice fades out quickly rather than stopping dead.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Stealing {
+    #[default]
+    Oldest,
+    Quietest,
+    // Always retriggers a voice already playing the same pitch, even below
+    // the limit; otherwise steals the oldest one
+    SameNote,
+}
+
+pub const VOICE_LIMIT: usize = 16;
+
+// How long a stolen voice takes to fade out
+pub const STEAL_FADE: f64 = 0.005;
+
+// Plays any number of overlapping notes on one instrument, mixed into a
+// single stream. Voices start at whatever point the stream is at when
+// their note-on arrives, and the instrument effects run on the mix (one
+// chain per channel in stereo).
+pub struct Poly<'a> {
+    instrument: &'a Instrument,
+    voices: Vec<Voice<'a>>,
+    limit: usize,
+    stealing: Stealing,
+    effects: [Effects; 2],
+    sample_rate: i32,
+    position: usize,
+    sounding: usize,
+    scratch: Stereo,
+}
+
+impl<'a> Poly<'a> {
+    pub fn new(instrument: &'a Instrument) -> Self {
+        Self {
+            instrument,
+            voices: Vec::new(),
+            limit: VOICE_LIMIT,
+            stealing: Stealing::default(),
+            effects: [instrument.effects.fresh(), instrument.effects.fresh()],
+            sample_rate: instrument.sample_rate(),
+            position: 0,
+            sounding: 0,
+            scratch: Stereo::default(),
+        }
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit.max(1);
+        self
+    }
+
+    pub fn with_stealing(mut self, stealing: Stealing) -> Self {
+        self.stealing = stealing;
+        self
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: i32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+
+    // Frames rendered so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // Voices playing, leaving out stolen ones on their way out
+    pub fn voices(&self) -> usize {
+        self.voices.iter().filter(|x| !x.is_fading()).count()
+    }
+
+    // Starts a note that sounds until `note_off`. Rests are ignored.
+    pub fn note_on(&mut self, note: Note, volume: f64) {
+        if note.freq().is_some() {
+            let voice = self.instrument.note_on(note, volume);
+            self.start(voice);
+        }
+    }
+
+    // Starts a note released `secs` from now
+    pub fn note_on_for(&mut self, note: Note, volume: f64, secs: f64) {
+        if note.freq().is_some() {
+            let voice = self.instrument.note_on(note, volume).with_duration(secs);
+            self.start(voice);
+        }
+    }
+
+    // Releases every voice playing the note's pitch
+    pub fn note_off(&mut self, note: Note) {
+        self.voices
+            .iter_mut()
+            .filter(|x| x.note().freq() == note.freq())
+            .for_each(|x| x.note_off());
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.voices.iter_mut().for_each(|x| x.note_off());
+    }
+
+    // Frames left until everything, tails included, has died out. Unknown
+    // while any voice is still held.
+    pub fn remaining(&self) -> Option<usize> {
+        let voices = self
+            .voices
+            .iter()
+            .try_fold(0, |frames, x| x.remaining().map(|y| frames.max(y)))?;
+        let end = self.sounding.max(self.position + voices);
+        let tail = crate::samples(self.effects[0].tail(), self.sample_rate);
+        Some((end + tail).saturating_sub(self.position))
+    }
+
+    // Renders both channels, with every voice placed at `pan` (-1.0 to 1.0)
+    pub fn process_stereo(&mut self, pan: f64, left: &mut [f64], right: &mut [f64]) -> usize {
+        let frames = self.available(left.len().min(right.len()));
+        left.fill(0.0);
+        right.fill(0.0);
+        let (left, right) = (&mut left[..frames], &mut right[..frames]);
+        self.scratch.resize(frames);
+
+        for voice in self.voices.iter_mut() {
+            let scratch = &mut self.scratch;
+            let rendered = voice.process_stereo(pan, &mut scratch.left, &mut scratch.right);
+            self.sounding = self.sounding.max(self.position + rendered);
+            for (sound, part) in [(&mut *left, &scratch.left), (&mut *right, &scratch.right)] {
+                sound.iter_mut().zip(part.iter()).for_each(|(x, y)| *x += y);
+            }
+        }
+        self.voices.retain(|x| !x.is_finished());
+        for (sound, effects) in [left, right].into_iter().zip(self.effects.iter_mut()) {
+            effects.process(sound, self.sample_rate);
+        }
+
+        self.position += frames;
+        frames
+    }
+
+    fn available(&self, frames: usize) -> usize {
+        match self.remaining() {
+            Some(left) => frames.min(left),
+            None => frames,
+        }
+    }
+
+    fn start(&mut self, voice: Voice<'a>) {
+        let voice = voice.with_sample_rate(self.sample_rate).dry();
+        self.voices.retain(|x| !x.is_finished());
+        if let Some(i) = self.steal(voice.note()) {
+            self.voices[i].fade_out(STEAL_FADE);
+        }
+        self.voices.push(voice);
+    }
+
+    // Voice to make room for the note, if one has to go
+    fn steal(&self, note: Note) -> Option<usize> {
+        let voices = &self.voices;
+        let live: Vec<usize> = (0..voices.len())
+            .filter(|&i| !voices[i].is_fading())
+            .collect();
+        let same = live
+            .iter()
+            .copied()
+            .find(|&i| voices[i].note().freq() == note.freq());
+        if self.stealing == Stealing::SameNote && same.is_some() {
+            return same;
+        }
+        if live.len() < self.limit {
+            return None;
+        }
+        let candidates = live.into_iter();
+        match self.stealing {
+            Stealing::Oldest | Stealing::SameNote => {
+                candidates.max_by_key(|&i| voices[i].elapsed())
+            }
+            Stealing::Quietest => {
+                candidates.min_by(|&a, &b| voices[a].level().total_cmp(&voices[b].level()))
+            }
+        }
+    }
+}
+
+impl Stream for Poly<'_> {
+    fn process(&mut self, buffer: &mut [f64]) -> usize {
+        let frames = self.available(buffer.len());
+        buffer.fill(0.0);
+        let sound = &mut buffer[..frames];
+        self.scratch.resize(frames);
+
+        for voice in self.voices.iter_mut() {
+            let rendered = voice.process(&mut self.scratch.left);
+            self.sounding = self.sounding.max(self.position + rendered);
+            sound
+                .iter_mut()
+                .zip(self.scratch.left.iter())
+                .for_each(|(x, y)| *x += y);
+        }
+        self.voices.retain(|x| !x.is_finished());
+        self.effects[0].process(sound, self.sample_rate);
+
+        self.position += frames;
+        frames
+    }
+
+    fn is_finished(&self) -> bool {
+        self.remaining() == Some(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect::Delay;
+    use crate::envelope::{Fixed, ASR, RAR};
+    use crate::generator::{chain::Chain, simple::Simple};
+    use crate::oscillator::{Oscillator, Phasor};
+    use crate::testing::{assert_chord_is_sum, drain, synth};
+    use crate::Rack;
+    use note::*;
+
+    #[test]
+    fn chord_is_sum_of_notes() {
+        let chord = [
+            note![C: C4, 1 / 4],
+            note![E: C4, 1 / 8],
+            note![G: C4, 1 / 16],
+        ];
+        assert_chord_is_sum(&synth(), &chord, 0.5);
+    }
+
+    #[test]
+    fn phased_chord_is_sum_of_notes() {
+        let mut chain = Chain::phased(Oscillator::Saw);
+        chain.mul(Phasor::new(Oscillator::Sine));
+        let synth = Instrument::new(chain, RAR::new(0.01, 0.05));
+        assert_chord_is_sum(&synth, &[note![C: C4, 1 / 8], note![G: C4, 1 / 8]], 0.5);
+    }
+
+    #[test]
+    fn overlapping_notes_start_where_stream_is() {
+        let synth = Instrument::new(Simple::default(), ASR::new(0.0, 1.0, 0.1));
+        let mut poly = synth.poly();
+        let mut buffer = vec![0.0; 1000];
+
+        poly.note_on_for(note![A: C4, 1 / 4], 1.0, 0.1);
+        poly.process(&mut buffer);
+        poly.note_on_for(note![C: C4, 1 / 4], 1.0, 0.1);
+        assert_eq!(2, poly.voices());
+
+        let mut result = buffer.clone();
+        result.extend(drain(&mut poly, 1000));
+        let late = crate::samples(0.2, 44100) + 1000;
+        assert_eq!(late, result.len());
+        assert_eq!(0, poly.voices());
+    }
+
+    #[test]
+    fn held_notes_keep_stream_open() {
+        let synth = Instrument::new(Simple::default(), ASR::new(0.0, 10.0, 0.1));
+        let mut poly = synth.poly();
+        poly.note_on(note![A: C4, 1 / 4], 1.0);
+        assert_eq!(None, poly.remaining());
+
+        poly.note_off(note![A: C4, 1 / 1]);
+        assert_eq!(Some(crate::samples(0.1, 44100)), poly.remaining());
+    }
+
+    #[test]
+    fn voice_limit_steals() {
+        let synth = Instrument::new(Simple::default(), RAR::new(0.5, 0.5));
+        let notes = [
+            note![C: C4, 1 / 4],
+            note![E: C4, 1 / 4],
+            note![G: C4, 1 / 4],
+        ];
+        // Long enough for stolen voices to fade out
+        let mut buffer = vec![0.0; 1000];
+
+        let mut poly = synth.poly().with_limit(2);
+        for note in notes {
+            poly.note_on(note, 1.0);
+            poly.process(&mut buffer);
+        }
+        assert_eq!(2, poly.voices());
+        assert!(poly
+            .voices
+            .iter()
+            .all(|x| x.note().freq() != notes[0].freq()));
+
+        // Attack is still rising, so the newest voice is the quietest
+        let mut poly = synth.poly().with_limit(2).with_stealing(Stealing::Quietest);
+        for note in notes {
+            poly.note_on(note, 1.0);
+            poly.process(&mut buffer);
+        }
+        assert!(poly
+            .voices
+            .iter()
+            .all(|x| x.note().freq() != notes[1].freq()));
+
+        let mut poly = synth.poly().with_limit(2).with_stealing(Stealing::SameNote);
+        for note in [notes[0], notes[1], notes[0]] {
+            poly.note_on(note, 1.0);
+            poly.process(&mut buffer);
+        }
+        assert!(poly
+            .voices
+            .iter()
+            .any(|x| x.note().freq() == notes[1].freq()));
+        assert_eq!(1000, poly.voices.iter().map(|x| x.elapsed()).min().unwrap());
+
+        // Same pitch retriggers even with room to spare
+        let mut poly = synth.poly().with_stealing(Stealing::SameNote);
+        for note in [notes[0], notes[0], notes[1]] {
+            poly.note_on(note, 1.0);
+            poly.process(&mut buffer);
+        }
+        assert_eq!(2, poly.voices());
+    }
+
+    #[test]
+    fn stolen_voice_fades_out() {
+        let synth = Instrument::new(Simple::default(), Fixed {});
+        let (first, second) = (note![A: C4, 1 / 4], note![E: C4, 1 / 4]);
+        let mut poly = synth.poly().with_limit(1);
+        // Stolen close to the top of a cycle
+        let steal = 1025;
+        let mut sound = vec![0.0; steal + 1000];
+
+        poly.note_on(first, 1.0);
+        poly.process(&mut sound[..steal]);
+        poly.note_on(second, 1.0);
+        assert_eq!(1, poly.voices());
+        poly.process(&mut sound[steal..]);
+        assert_eq!(1, poly.voices.len());
+
+        // Without the new note, the stolen one eases down to silence
+        let newer = synth.play(120.0, second, 1.0);
+        sound[steal..]
+            .iter_mut()
+            .zip(newer.iter())
+            .for_each(|(x, y)| *x -= y);
+        assert!(sound[steal - 1].abs() > 0.5);
+        assert!(sound.windows(2).all(|w| (w[1] - w[0]).abs() < 0.07));
+        let faded = steal + crate::samples(STEAL_FADE, 44100);
+        assert!(sound[faded..].iter().all(|x| x.abs() < 1e-9));
+    }
+
+    #[test]
+    fn effects_run_once_on_mix() {
+        let synth = Instrument::new(Simple::default(), RAR::new(0.01, 0.05))
+            .with_effect(Delay::new(0.25, 0.5));
+        let chord = [note![C: C4, 1 / 16], note![G: C4, 1 / 16]];
+        let length = crate::samples(chord[0].secs(120.0) + 0.05 + 2.5, 44100);
+        assert_eq!(length, synth.play_chord(120.0, &chord, 1.0).len());
+
+        let mut rack = Rack::default();
+        rack.add(synth);
+        assert_eq!(length, rack.play_chord(120.0, &chord, 1.0).len());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::mixer::Buffer;
+use crate::poly::Poly;
+use crate::stereo::Stereo;
+use crate::voice::Stream;
+use note::Note;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Event {
+    pub at: f64,
+    pub note: Note,
+    pub volume: f64,
+}
+
+// Notes placed on a timeline measured in beats. Rests take up time but
+// don't sound.
+#[derive(Clone, Debug, Default)]
+pub struct Sequence {
+    events: Vec<Event>,
+    end: f64,
+}
+
+// Length of a note in beats
+pub fn beats(note: Note) -> f64 {
+    note.secs(60.0)
+}
+
+impl Sequence {
+    // Notes played one after another, starting at beat zero
+    pub fn melody(notes: &[Note]) -> Self {
+        let mut sequence = Self::default();
+        notes.iter().for_each(|x| {
+            sequence.then(*x);
+        });
+        sequence
+    }
+
+    pub fn add(&mut self, at: f64, note: Note) -> &mut Self {
+        self.add_with_volume(at, note, 1.0)
+    }
+
+    pub fn add_with_volume(&mut self, at: f64, note: Note, volume: f64) -> &mut Self {
+        let at = at.max(0.0);
+        let i = self.events.partition_point(|x| x.at <= at);
+        self.events.insert(i, Event { at, note, volume });
+        self.end = self.end.max(at + beats(note));
+        self
+    }
+
+    // Places the note right where the sequence currently ends
+    pub fn then(&mut self, note: Note) -> &mut Self {
+        self.add(self.end, note)
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    // Beat at which the last note (or rest) is over
+    pub fn end(&self) -> f64 {
+        self.end
+    }
+
+    // Plays the events through the voice manager, leaving room for release
+    // and effect tails past the end of the sequence.
+    pub fn render(&self, poly: &mut Poly, tempo: &Tempo, volume: f64) -> Vec<f64> {
+        self.perform(poly, tempo, volume, |poly, sound: &mut Vec<f64>, from| {
+            poly.process(&mut sound[from..]);
+        })
+    }
+
+    // Same in stereo, with the voices placed at `pan`
+    pub fn render_stereo(&self, poly: &mut Poly, tempo: &Tempo, volume: f64, pan: f64) -> Stereo {
+        self.perform(poly, tempo, volume, |poly, sound: &mut Stereo, from| {
+            poly.process_stereo(pan, &mut sound.left[from..], &mut sound.right[from..]);
+        })
+    }
+
+    // Feeds the events to the voice manager, with `process` rendering it
+    // from the given frame up to the end of the buffer
+    fn perform<B, F>(&self, poly: &mut Poly, tempo: &Tempo, volume: f64, mut process: F) -> B
+    where
+        B: Buffer,
+        F: FnMut(&mut Poly, &mut B, usize),
+    {
+        let sample_rate = poly.sample_rate();
+        let position = |beat: f64| crate::samples(tempo.secs(beat), sample_rate);
+        let mut result = B::silence(0);
+
+        for event in self.events.iter() {
+            let start = position(event.at);
+            if start > result.frames() {
+                let from = result.frames();
+                result.resize(start);
+                process(poly, &mut result, from);
+            }
+            let secs = tempo.length(event.at, beats(event.note));
+            poly.note_on_for(event.note, volume * event.volume, secs);
+        }
+
+        let from = result.frames();
+        let left = poly.remaining().unwrap_or_default();
+        result.resize(position(self.end).max(from + left));
+        process(poly, &mut result, from);
+        result
+    }
+}
+
+// Tempo over the course of a piece, as changes in BPM taking effect at
+// given beats.
+#[derive(Clone, Debug)]
+pub struct Tempo {
+    changes: Vec<(f64, f64)>,
+}
+
+impl Tempo {
+    pub fn new(bpm: f64) -> Self {
+        Self {
+            changes: vec![(0.0, bpm)],
+        }
+    }
+
+    pub fn change(&mut self, at: f64, bpm: f64) -> &mut Self {
+        let at = at.max(0.0);
+        self.changes.retain(|x| x.0 != at);
+        let i = self.changes.partition_point(|x| x.0 < at);
+        self.changes.insert(i, (at, bpm));
+        self
+    }
+
+    pub fn bpm_at(&self, beat: f64) -> f64 {
+        self.changes
+            .iter()
+            .take_while(|x| x.0 <= beat)
+            .last()
+            .map_or(self.changes[0].1, |x| x.1)
+    }
+
+    // Time from the start of the piece to the given beat
+    pub fn secs(&self, beat: f64) -> f64 {
+        let mut secs = 0.0;
+        for (i, (at, bpm)) in self.changes.iter().enumerate() {
+            let until = self.changes.get(i + 1).map_or(f64::INFINITY, |x| x.0);
+            let from = if i == 0 { 0.0 } else { *at };
+            if beat <= from {
+                break;
+            }
+            secs += (beat.min(until) - from) * 60.0 / bpm;
+        }
+        secs
+    }
+
+    // How long `beats` last when starting at beat `at`
+    pub fn length(&self, at: f64, beats: f64) -> f64 {
+        self.secs(at + beats) - self.secs(at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect::Delay;
+    use crate::envelope::{ASR, RAR};
+    use crate::generator::simple::Simple;
+    use crate::testing::{close, synth};
+    use crate::{Instrument, Rack};
+    use note::*;
+
+    #[test]
+    fn melody_places_notes_back_to_back() {
+        let sequence = Sequence::melody(&[note![C: C4, 1 / 4], pause![1 / 8], note![E: C4, 1 / 8]]);
+        let starts: Vec<f64> = sequence.events().iter().map(|x| x.at).collect();
+        assert_eq!(vec![0.0, 1.0, 1.5], starts);
+        assert_eq!(2.0, sequence.end());
+    }
+
+    #[test]
+    fn notes_land_on_their_beats() {
+        let synth = synth();
+        let first = note![C: C4, 1 / 8];
+        let second = note![G: C4, 1 / 8];
+        let mut sequence = Sequence::default();
+        sequence.add(2.0, second).add(0.0, first);
+
+        let sound = synth.play_sequence(120.0, &sequence, 1.0);
+        assert_eq!(crate::samples(1.25 + 0.05, 44100), sound.len());
+
+        let one = synth.play(120.0, first, 1.0);
+        let two = synth.play(120.0, second, 1.0);
+        let offset = crate::samples(1.0, 44100);
+        assert!(close(&one, &sound[..one.len()]));
+        assert!(sound[one.len()..offset].iter().all(|&x| x == 0.0));
+        assert!(close(&two, &sound[offset..offset + two.len()]));
+    }
+
+    #[test]
+    fn tails_overlap_next_notes() {
+        let synth = Instrument::new(Simple::default(), ASR::new(0.0, 0.1, 0.5));
+        let notes = [note![C: C4, 1 / 8], note![E: C4, 1 / 8]];
+        let sequence = Sequence::melody(&notes);
+
+        let sound = synth.play_sequence(120.0, &sequence, 1.0);
+        let one = synth.play(120.0, notes[0], 1.0);
+        let two = synth.play(120.0, notes[1], 1.0);
+        let offset = crate::samples(0.25, 44100);
+        assert_eq!(offset + two.len(), sound.len());
+        for (i, x) in sound.iter().enumerate() {
+            let a = one.get(i).unwrap_or(&0.0);
+            let b = i
+                .checked_sub(offset)
+                .and_then(|j| two.get(j))
+                .unwrap_or(&0.0);
+            assert!((a + b - x).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn tempo_changes_stretch_time() {
+        let mut tempo = Tempo::new(120.0);
+        tempo.change(4.0, 60.0).change(8.0, 240.0);
+
+        assert_eq!(120.0, tempo.bpm_at(3.0));
+        assert_eq!(60.0, tempo.bpm_at(4.0));
+        assert_eq!(240.0, tempo.bpm_at(100.0));
+        assert_eq!(1.0, tempo.secs(2.0));
+        assert_eq!(3.0, tempo.secs(5.0));
+        assert_eq!(6.0, tempo.secs(8.0));
+        assert_eq!(6.5, tempo.secs(10.0));
+        assert_eq!(1.5, tempo.length(3.0, 2.0));
+    }
+
+    #[test]
+    fn rack_plays_sequence_with_tails() {
+        let mut rack = Rack::default();
+        rack.add(synth());
+        rack.add_with_volume(Instrument::new(Simple::square(), RAR::new(0.05, 0.0)), 0.5);
+        rack.add_effect(Delay::new(0.1, 0.0));
+        let sequence = Sequence::melody(&[note![C: C4, 1 / 8], pause![1 / 2]]);
+
+        let sound = rack.play_sequence(120.0, &sequence, 1.0);
+        let (tail, end) = (crate::samples(0.1, 44100), crate::samples(1.25, 44100));
+        assert_eq!(end + tail, sound.len());
+
+        // Both envelopes are done by 60ms, so this is the echo
+        let echo = &sound[crate::samples(0.1, 44100)..crate::samples(0.15, 44100)];
+        assert!(echo.iter().any(|x| x.abs() > 0.1));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::master::{Levels, Master};
+use crate::sequence::{Sequence, Tempo};
+use crate::stereo::Stereo;
+use crate::{Instrument, SAMPLE_RATE};
+use std::cell::Cell;
+
+pub struct Track {
+    instrument: Instrument,
+    sequence: Sequence,
+    volume: f64,
+    pan: f64,
+}
+
+impl Track {
+    pub fn new(instrument: Instrument, sequence: Sequence) -> Self {
+        Self {
+            instrument,
+            sequence,
+            volume: 1.0,
+            pan: 0.0,
+        }
+    }
+
+    pub fn with_volume(mut self, volume: f64) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub fn with_pan(mut self, pan: f64) -> Self {
+        self.pan = pan.clamp(-1.0, 1.0);
+        self
+    }
+
+    fn render(&self, tempo: &Tempo, sample_rate: i32) -> Vec<f64> {
+        let mut poly = self.instrument.poly().with_sample_rate(sample_rate);
+        self.sequence.render(&mut poly, tempo, self.volume)
+    }
+
+    fn render_stereo(&self, tempo: &Tempo, sample_rate: i32) -> Stereo {
+        let mut poly = self.instrument.poly().with_sample_rate(sample_rate);
+        self.sequence
+            .render_stereo(&mut poly, tempo, self.volume, self.pan)
+    }
+}
+
+// Tracks played together against a shared tempo map
+pub struct Song {
+    tracks: Vec<Track>,
+    tempo: Tempo,
+    master: Master,
+    levels: Cell<Levels>,
+    sample_rate: i32,
+}
+
+impl Song {
+    pub fn new(bpm: f64) -> Self {
+        Self {
+            tracks: Vec::new(),
+            tempo: Tempo::new(bpm),
+            master: Master::default(),
+            levels: Cell::default(),
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+
+    pub fn with_tempo(mut self, tempo: Tempo) -> Self {
+        self.tempo = tempo;
+        self
+    }
+
+    pub fn with_master(mut self, master: Master) -> Self {
+        self.master = master;
+        self
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: i32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+
+    // Peak and RMS of the last render, as it left the master
+    pub fn levels(&self) -> Levels {
+        self.levels.get()
+    }
+
+    pub fn tempo(&mut self) -> &mut Tempo {
+        &mut self.tempo
+    }
+
+    pub fn add(&mut self, track: Track) {
+        self.tracks.push(track);
+    }
+
+    // Mono mixdown, pan left out
+    pub fn render(&self) -> Vec<f64> {
+        let mut result = Vec::new();
+        for track in self.tracks.iter() {
+            let part = track.render(&self.tempo, self.sample_rate);
+            mix_into(&mut result, &part, 1.0);
+        }
+        let levels = self.master.process(&mut result, self.sample_rate);
+        self.levels.set(levels);
+        result
+    }
+
+    pub fn render_stereo(&self) -> Stereo {
+        let mut result = Stereo::default();
+        for track in self.tracks.iter() {
+            let part = track.render_stereo(&self.tempo, self.sample_rate);
+            result.mix(&part, 1.0);
+        }
+        let levels = self.master.process_stereo(&mut result, self.sample_rate);
+        self.levels.set(levels);
+        result
+    }
+}
+
+fn mix_into(buffer: &mut Vec<f64>, part: &[f64], gain: f64) {
+    if buffer.len() < part.len() {
+        buffer.resize(part.len(), 0.0);
+    }
+    buffer
+        .iter_mut()
+        .zip(part.iter())
+        .for_each(|(x, y)| *x += y * gain);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::RAR;
+    use crate::testing::synth;
+    use note::*;
+
+    #[test]
+    fn tracks_mix_down() {
+        let bass = Sequence::melody(&[note![C: C3, 1 / 4], note![G: C3, 1 / 4]]);
+        let lead = Sequence::melody(&[pause![1 / 4], note![E: C5, 1 / 2]]);
+        let mut song = Song::new(120.0);
+        song.add(Track::new(synth(), bass.clone()).with_pan(-1.0));
+        song.add(Track::new(synth(), lead.clone()).with_volume(0.5));
+
+        let bass = synth().play_sequence(120.0, &bass, 1.0);
+        let lead = synth().play_sequence(120.0, &lead, 0.5);
+        let mono = song.render();
+        assert_eq!(bass.len().max(lead.len()), mono.len());
+        for (i, x) in mono.iter().enumerate() {
+            let sum = bass.get(i).unwrap_or(&0.0) + lead.get(i).unwrap_or(&0.0);
+            assert!((sum - x).abs() < 1e-9);
+        }
+
+        let (left, right) = song.render_stereo().split();
+        let centre = crate::stereo::pan(0.0).1;
+        for (i, x) in right.iter().enumerate() {
+            let lead = lead.get(i).unwrap_or(&0.0) * centre;
+            assert!((lead - x).abs() < 1e-9);
+        }
+        assert!(left.iter().zip(right.iter()).any(|(l, r)| l != r));
+    }
+
+    #[test]
+    fn stereo_keeps_spread_and_pan_modulation() {
+        use crate::generator::detuned::Freq;
+        use crate::lfo::LFO;
+        use crate::modulation::{Destination, Source};
+
+        let synth = || {
+            Instrument::new(Freq::square(3.0).with_spread(1.0), RAR::new(0.01, 0.05))
+                .with_modulation(Source::lfo(LFO::sine(4.0)), Destination::Pan, 0.5)
+        };
+        let note = note![A: C4, 1 / 4];
+        let mut song = Song::new(120.0);
+        song.add(Track::new(synth(), Sequence::melody(&[note])).with_pan(0.3));
+
+        let expected = synth().render_stereo(120.0, note, 1.0, 0.3, 44100);
+        let sound = song.render_stereo();
+        assert_eq!(expected.len(), sound.len());
+        for (a, b) in [
+            (&expected.left, &sound.left),
+            (&expected.right, &sound.right),
+        ] {
+            assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-9));
+        }
+    }
+
+    #[test]
+    fn tempo_changes_move_notes() {
+        let melody = Sequence::melody(&[note![C: C4, 1 / 4], note![E: C4, 1 / 4]]);
+        let mut song = Song::new(120.0);
+        song.tempo().change(1.0, 60.0);
+        song.add(Track::new(synth(), melody));
+
+        // Half a second for the first beat, a second for the next
+        let sound = song.render();
+        assert_eq!(crate::samples(1.5 + 0.05, 44100), sound.len());
+        let second = crate::samples(0.5, 44100);
+        assert!(sound[second - 100..second].iter().all(|&x| x == 0.0));
+        assert!(sound[second..second + 1000].iter().any(|&x| x != 0.0));
+    }
+}
@@ -0,0 +1,119 @@
+use std::f64::consts::PI;
+
+// Constant-power pan law: -1.0 is hard left, 1.0 hard right, and the
+// centre gets about -3dB on each side.
+pub fn pan(position: f64) -> (f64, f64) {
+    let angle = (position.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+    (angle.cos(), angle.sin())
+}
+
+// Two channels kept side by side. Writers that want a single buffer (such
+// as WaveRenderer with two channels) take the interleaved form instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stereo {
+    pub left: Vec<f64>,
+    pub right: Vec<f64>,
+}
+
+impl Stereo {
+    pub fn new(frames: usize) -> Self {
+        Self {
+            left: vec![0.0; frames],
+            right: vec![0.0; frames],
+        }
+    }
+
+    // Places a mono sound at the given pan position
+    pub fn panned(sound: &[f64], position: f64) -> Self {
+        let (left, right) = pan(position);
+        Self {
+            left: sound.iter().map(|x| x * left).collect(),
+            right: sound.iter().map(|x| x * right).collect(),
+        }
+    }
+
+    pub fn from_interleaved(samples: &[f64]) -> Self {
+        Self {
+            left: samples.iter().step_by(2).copied().collect(),
+            right: samples.iter().skip(1).step_by(2).copied().collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.left.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty()
+    }
+
+    pub fn resize(&mut self, frames: usize) {
+        self.left.resize(frames, 0.0);
+        self.right.resize(frames, 0.0);
+    }
+
+    // Adds the other sound on top, growing to fit it
+    pub fn mix(&mut self, other: &Stereo, gain: f64) {
+        if self.len() < other.len() {
+            self.resize(other.len());
+        }
+        for (x, y) in [
+            (&mut self.left, &other.left),
+            (&mut self.right, &other.right),
+        ] {
+            x.iter_mut().zip(y.iter()).for_each(|(x, y)| *x += y * gain);
+        }
+    }
+
+    // Left, right, left, right...
+    pub fn interleaved(&self) -> Vec<f64> {
+        self.left
+            .iter()
+            .zip(self.right.iter())
+            .flat_map(|(l, r)| [*l, *r])
+            .collect()
+    }
+
+    pub fn split(self) -> (Vec<f64>, Vec<f64>) {
+        (self.left, self.right)
+    }
+
+    // Both channels averaged back down
+    pub fn mono(&self) -> Vec<f64> {
+        self.left
+            .iter()
+            .zip(self.right.iter())
+            .map(|(l, r)| (l + r) / 2.0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pan_keeps_power() {
+        for position in [-1.0, -0.3, 0.0, 0.5, 1.0] {
+            let (l, r) = pan(position);
+            assert!((1.0 - l * l - r * r).abs() < 1e-9);
+        }
+        assert!(pan(-1.0).1.abs() < 1e-9);
+        assert!(pan(1.0).0.abs() < 1e-9);
+    }
+
+    #[test]
+    fn interleaves_and_back() {
+        let sound = Stereo {
+            left: vec![1.0, 2.0, 3.0],
+            right: vec![-1.0, -2.0, -3.0],
+        };
+        let samples = sound.interleaved();
+        assert_eq!(vec![1.0, -1.0, 2.0, -2.0, 3.0, -3.0], samples);
+        assert_eq!(sound, Stereo::from_interleaved(&samples));
+
+        let mut mixed = Stereo::new(1);
+        mixed.mix(&sound, 2.0);
+        assert_eq!((vec![2.0, 4.0, 6.0], vec![-2.0, -4.0, -6.0]), mixed.split());
+    }
+}
@@ -0,0 +1,74 @@
+// Helpers shared by the unit tests
+use crate::envelope::RAR;
+use crate::generator::simple::Simple;
+use crate::voice::Stream;
+use crate::{Instrument, Signal};
+use note::Note;
+
+// Plain sine with a short attack and release
+pub fn synth() -> Instrument {
+    Instrument::new(Simple::default(), RAR::new(0.01, 0.05))
+}
+
+// Signal that never moves off its value
+pub struct Constant(pub f64);
+
+impl Signal for Constant {
+    fn value_at(&self, _t: f64, _frequency: f64, _sample_rate: i32) -> f64 {
+        self.0
+    }
+}
+
+// Same length, and every sample the same but for rounding
+pub fn close(a: &[f64], b: &[f64]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-9)
+}
+
+pub fn assert_close(expected: f64, actual: f64) {
+    assert!(
+        (expected - actual).abs() < 1e-9,
+        "{} vs {}",
+        expected,
+        actual
+    );
+}
+
+// Upward zero crossings, i.e. cycles for anything periodic
+pub fn crossings(sound: &[f64]) -> usize {
+    sound
+        .windows(2)
+        .filter(|w| w[0] <= 0.0 && w[1] > 0.0)
+        .count()
+}
+
+// Runs the stream to its end, `block` frames at a time
+pub fn drain(stream: &mut impl Stream, block: usize) -> Vec<f64> {
+    let mut result = Vec::new();
+    let mut buffer = vec![0.0; block];
+    while !stream.is_finished() {
+        let frames = stream.process(&mut buffer);
+        result.extend_from_slice(&buffer[..frames]);
+    }
+    result
+}
+
+// Overlapping notes, whether played as a chord or streamed through a poly in
+// small blocks, add up to the same notes played one at a time
+pub fn assert_chord_is_sum(synth: &Instrument, notes: &[Note], volume: f64) {
+    let mut poly = synth.poly();
+    notes
+        .iter()
+        .for_each(|x| poly.note_on_for(*x, volume, x.secs(120.0)));
+    let streamed = drain(&mut poly, 64);
+    assert!(close(&synth.play_chord(120.0, notes, volume), &streamed));
+
+    let parts: Vec<Vec<f64>> = notes
+        .iter()
+        .map(|x| synth.play(120.0, *x, volume))
+        .collect();
+    assert_eq!(parts.iter().map(Vec::len).max(), Some(streamed.len()));
+    for (i, x) in streamed.iter().enumerate() {
+        let sum: f64 = parts.iter().filter_map(|p| p.get(i)).sum();
+        assert!((sum - x).abs() < 1e-9, "{} vs {} at {}", sum, x, i);
+    }
+}
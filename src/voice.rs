@@ -0,0 +1,564 @@
+use crate::effect::Effects;
+use crate::filter;
+use crate::master::Stage;
+use crate::mixer::Router;
+use crate::modulation::{Context, Destination, Params};
+use crate::{stereo, Generator, Instrument, Rack, State};
+use note::Note;
+
+// Pull-based rendering: each call fills the next block of the buffer and
+// reports how many frames were actually produced. Whatever is left over
+// once the sound is done gets silenced.
+pub trait Stream {
+    fn process(&mut self, buffer: &mut [f64]) -> usize;
+    fn is_finished(&self) -> bool;
+}
+
+// A single note played on an instrument. It sounds from note-on until it is
+// released, then keeps going for as long as the envelope's release lasts.
+pub struct Voice<'a> {
+    instrument: &'a Instrument,
+    note: Note,
+    volume: f64,
+    sample_rate: i32,
+    position: usize,
+    off: Option<f64>,
+    fade: Option<(usize, usize)>,
+    clock: f64,
+    state: Vec<State>,
+    modulators: Vec<State>,
+    lanes: [Lane; 2],
+}
+
+// Per-channel processing state: the left (or only) channel runs in the
+// first lane, the right one in the second.
+struct Lane {
+    filters: Vec<filter::State>,
+    effects: Effects,
+}
+
+impl Lane {
+    fn new(instrument: &Instrument) -> Self {
+        Self {
+            filters: vec![Default::default(); instrument.filters.len()],
+            effects: instrument.effects.fresh(),
+        }
+    }
+}
+
+impl<'a> Voice<'a> {
+    pub fn new(instrument: &'a Instrument, note: Note, volume: f64) -> Self {
+        Self {
+            instrument,
+            note,
+            volume,
+            sample_rate: instrument.sample_rate(),
+            position: 0,
+            off: None,
+            fade: None,
+            clock: 0.0,
+            state: Vec::new(),
+            modulators: Vec::new(),
+            lanes: [Lane::new(instrument), Lane::new(instrument)],
+        }
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: i32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    // Schedules the note-off `secs` after note-on
+    pub fn with_duration(mut self, secs: f64) -> Self {
+        self.off = Some(secs);
+        self
+    }
+
+    pub fn note_off(&mut self) {
+        let now = self.position as f64 / self.sample_rate as f64;
+        self.off = Some(self.off.map_or(now, |off| off.min(now)));
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.off.is_some()
+    }
+
+    // Cuts the voice short with a quick fade to silence over `secs`, e.g.
+    // when it gets stolen
+    pub fn fade_out(&mut self, secs: f64) {
+        let length = crate::samples(secs, self.sample_rate).max(1);
+        self.fade = Some((self.position, length));
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    pub fn note(&self) -> Note {
+        self.note
+    }
+
+    // Frames rendered since note-on
+    pub fn elapsed(&self) -> usize {
+        self.position
+    }
+
+    // Current envelope level, for picking the quietest voice
+    pub fn level(&self) -> f64 {
+        let t = self.position as f64 / self.sample_rate as f64;
+        let off = self.off.unwrap_or(f64::INFINITY);
+        self.instrument.envelope.released_at(t, off, self.volume)
+    }
+
+    // Total length including the release and effect tails, known once
+    // released or fading out
+    pub fn frames(&self) -> Option<usize> {
+        let released = self.off.map(|off| {
+            let release = self.instrument.envelope.release();
+            let tail = self.lanes[0].effects.tail();
+            crate::samples(off + release + tail, self.sample_rate)
+        });
+        match self.fade {
+            Some((start, length)) => {
+                Some(released.map_or(start + length, |x| x.min(start + length)))
+            }
+            None => released,
+        }
+    }
+
+    pub fn remaining(&self) -> Option<usize> {
+        self.frames().map(|end| end.saturating_sub(self.position))
+    }
+
+    // Leaves the instrument effects to whoever mixes this voice
+    pub(crate) fn dry(mut self) -> Self {
+        self.lanes
+            .iter_mut()
+            .for_each(|x| x.effects = Effects::default());
+        self
+    }
+
+    // Renders both channels, placing the voice at `pan` (-1.0 to 1.0) with
+    // any pan modulation on top.
+    pub fn process_stereo(&mut self, pan: f64, left: &mut [f64], right: &mut [f64]) -> usize {
+        let frames = self.available(left.len().min(right.len()));
+        left[frames..].fill(0.0);
+        right[frames..].fill(0.0);
+        let (left, right) = (&mut left[..frames], &mut right[..frames]);
+
+        let modulation = self.modulation(frames);
+        let (times, params) = (&modulation.times, &modulation.params);
+        let (note, sample_rate) = (self.note, self.sample_rate);
+        self.generate(|x| x.process_stereo(note, times, params, sample_rate, left, right));
+        for (sound, lane) in [(&mut *left, 0), (&mut *right, 1)] {
+            sound
+                .iter_mut()
+                .zip(modulation.gains.iter())
+                .for_each(|(x, gain)| *x *= gain);
+            self.finish(lane, sound, &modulation.cutoffs);
+        }
+        for ((l, r), by) in left.iter_mut().zip(right.iter_mut()).zip(modulation.pans) {
+            let (gl, gr) = stereo::pan(pan + by);
+            *l *= gl;
+            *r *= gr;
+        }
+
+        self.position += frames;
+        frames
+    }
+}
+
+impl Stream for Voice<'_> {
+    fn process(&mut self, buffer: &mut [f64]) -> usize {
+        let frames = self.available(buffer.len());
+        let (sound, rest) = buffer.split_at_mut(frames);
+        rest.fill(0.0);
+
+        let (note, position, sample_rate) = (self.note, self.position, self.sample_rate);
+        let cutoffs = if self.instrument.matrix.is_empty() {
+            self.generate(|x| x.process(note, position, sample_rate, sound));
+            vec![0.0; frames]
+        } else {
+            let modulation = self.modulation(frames);
+            let (times, params) = (&modulation.times, &modulation.params);
+            self.generate(|x| x.process_with(note, times, params, sample_rate, sound));
+            sound
+                .iter_mut()
+                .zip(modulation.gains.iter())
+                .for_each(|(x, gain)| *x *= gain);
+            modulation.cutoffs
+        };
+        self.finish(0, sound, &cutoffs);
+
+        self.position += frames;
+        frames
+    }
+
+    fn is_finished(&self) -> bool {
+        self.frames().is_some_and(|end| self.position >= end)
+    }
+}
+
+impl Voice<'_> {
+    fn available(&self, frames: usize) -> usize {
+        match self.frames() {
+            Some(end) => frames.min(end.saturating_sub(self.position)),
+            None => frames,
+        }
+    }
+
+    // Runs the generator on this voice's own running state, as the
+    // instrument's other voices render in between
+    fn generate(&mut self, render: impl FnOnce(&dyn Generator)) {
+        let generator = self.instrument.generator.as_ref();
+        generator.load(&mut self.state.iter());
+        render(generator);
+        self.state.clear();
+        generator.save(&mut self.state);
+    }
+
+    // Filters, envelope and effects, in that order
+    fn finish(&mut self, lane: usize, sound: &mut [f64], cutoffs: &[f64]) {
+        for ((i, x), &octaves) in sound.iter_mut().enumerate().zip(cutoffs) {
+            let ctx = self.context(i);
+            let states = self.lanes[lane].filters.iter_mut();
+            for (filter, state) in self.instrument.filters.iter().zip(states) {
+                let cutoff = filter.cutoff_at(&ctx, octaves);
+                *x = filter.process(state, *x, cutoff, self.sample_rate);
+            }
+        }
+
+        let off = self.off.unwrap_or(f64::INFINITY);
+        sound.iter_mut().enumerate().for_each(|(i, x)| {
+            let t = (self.position + i) as f64 / self.sample_rate as f64;
+            *x *= self.instrument.envelope.released_at(t, off, self.volume);
+        });
+        if let Some((start, length)) = self.fade {
+            sound.iter_mut().enumerate().for_each(|(i, x)| {
+                let left = (start + length).saturating_sub(self.position + i);
+                *x *= left as f64 / length as f64;
+            });
+        }
+        self.lanes[lane].effects.process(sound, self.sample_rate);
+    }
+
+    fn context(&self, i: usize) -> Context {
+        Context {
+            t: (self.position + i) as f64 / self.sample_rate as f64,
+            off: self.off.unwrap_or(f64::INFINITY),
+            frequency: self.note.freq().unwrap_or(0.0),
+            velocity: self.volume,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    // Matrix output for the next frames, on this voice's own LFO state.
+    // Pitch modulation runs the generator on its own clock, which moves
+    // faster or slower than real time depending on the bend.
+    fn modulation(&mut self, frames: usize) -> Modulation {
+        let matrix = &self.instrument.matrix;
+        matrix.load(&mut self.modulators.iter());
+        let bends = matrix.targets(Destination::Pitch);
+        let mut modulation = Modulation::default();
+        for i in 0..frames {
+            let ctx = self.context(i);
+            modulation
+                .times
+                .push(if bends { self.clock } else { ctx.t });
+            modulation.params.push(matrix.params_at(&ctx));
+            let gain = 1.0 + matrix.value_at(Destination::Amplitude, &ctx);
+            modulation.gains.push(gain.max(0.0));
+            modulation
+                .cutoffs
+                .push(matrix.value_at(Destination::Cutoff, &ctx));
+            modulation
+                .pans
+                .push(matrix.value_at(Destination::Pan, &ctx));
+
+            let bend = matrix.value_at(Destination::Pitch, &ctx);
+            self.clock += 2.0_f64.powf(bend / 12.0) / self.sample_rate as f64;
+        }
+        self.modulators.clear();
+        matrix.save(&mut self.modulators);
+        modulation
+    }
+}
+
+// Matrix output, one value per frame
+#[derive(Default)]
+struct Modulation {
+    times: Vec<f64>,
+    params: Vec<Params>,
+    gains: Vec<f64>,
+    // Octaves added to the filter cutoffs
+    cutoffs: Vec<f64>,
+    pans: Vec<f64>,
+}
+
+// All the rack's instruments playing one note, routed and mixed, then run
+// through the master limiter and soft clip.
+pub struct Mix<'a> {
+    rack: &'a Rack,
+    voices: Vec<Voice<'a>>,
+    router: Router<'a>,
+    master: Stage,
+    position: usize,
+    rendered: usize,
+    parts: Vec<Vec<f64>>,
+}
+
+impl<'a> Mix<'a> {
+    pub fn new(rack: &'a Rack, note: Note, volume: f64) -> Self {
+        let voices = rack
+            .channels
+            .iter()
+            .map(|channel| {
+                channel
+                    .instrument
+                    .note_on(note, volume * channel.volume)
+                    .with_sample_rate(rack.sample_rate)
+            })
+            .collect();
+        Self {
+            rack,
+            voices,
+            router: Router::new::<Vec<f64>>(rack),
+            master: rack.master.stage(),
+            position: 0,
+            rendered: 0,
+            parts: vec![Vec::new(); rack.channels.len()],
+        }
+    }
+
+    pub fn with_duration(mut self, secs: f64) -> Self {
+        self.voices = self
+            .voices
+            .into_iter()
+            .map(|x| x.with_duration(secs))
+            .collect();
+        self
+    }
+
+    pub fn note_off(&mut self) {
+        self.voices.iter_mut().for_each(|x| x.note_off());
+    }
+
+    pub fn frames(&self) -> Option<usize> {
+        let tail = crate::samples(self.rack.tail(), self.rack.sample_rate);
+        self.voices
+            .iter()
+            .try_fold(0, |frames, x| x.frames().map(|y| frames.max(y)))
+            .map(|x| x + tail)
+    }
+}
+
+impl Stream for Mix<'_> {
+    fn process(&mut self, buffer: &mut [f64]) -> usize {
+        let frames = match self.frames() {
+            Some(end) => buffer.len().min(end.saturating_sub(self.position)),
+            None => buffer.len(),
+        };
+        buffer.fill(0.0);
+
+        // The master limiter runs late by its look-ahead, so it gets a head
+        // start the first time round
+        if self.rendered == 0 {
+            let mut ahead = vec![0.0; self.master.latency(self.rack.sample_rate)];
+            self.render(&mut ahead);
+        }
+        self.render(&mut buffer[..frames]);
+
+        self.position += frames;
+        frames
+    }
+
+    fn is_finished(&self) -> bool {
+        self.frames().is_some_and(|end| self.position >= end)
+    }
+}
+
+impl Mix<'_> {
+    // Next frames into the master, silent once the mix is over
+    fn render(&mut self, sound: &mut [f64]) {
+        let end = self.frames().unwrap_or(usize::MAX);
+        let frames = sound.len().min(end.saturating_sub(self.rendered));
+        for (voice, part) in self.voices.iter_mut().zip(self.parts.iter_mut()) {
+            part.resize(frames, 0.0);
+            voice.process(part);
+        }
+        let mix = self.router.process(&self.parts, frames);
+        sound[..frames].copy_from_slice(&mix);
+        sound[frames..].fill(0.0);
+
+        self.master.process(sound, self.rack.sample_rate);
+        self.rendered += sound.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect::{Delay, Reverb};
+    use crate::envelope::{ASR, RAR};
+    use crate::generator::{chain::Chain, simple::Simple};
+    use crate::lfo::LFO;
+    use crate::oscillator::Oscillator;
+    use crate::testing::drain;
+    use note::*;
+
+    #[test]
+    fn voice_blocks_match_whole_note() {
+        let mut chain = Chain::new(Oscillator::Square);
+        chain.add(LFO::sine(12.0));
+        let synth = Instrument::new(chain, RAR::new(0.015, 0.07));
+        let note = note![A: C4, 1 / 16];
+
+        let whole = synth.play(90.0, note, 1.0);
+        for block in [1, 64, 1000, whole.len() * 2] {
+            let mut voice = synth.voice(90.0, note, 1.0);
+            assert_eq!(whole, drain(&mut voice, block), "block of {}", block);
+        }
+    }
+
+    #[test]
+    fn finished_voice_silences_buffer() {
+        let synth = Instrument::new(Simple::square(), crate::envelope::Fixed {});
+        let mut voice = synth.voice(90.0, note![A: C4, 1 / 64], 1.0);
+        let frames = voice.frames().expect("note-off should be scheduled");
+        let mut buffer = vec![1.0; frames + 10];
+
+        assert_eq!(frames, voice.process(&mut buffer));
+        assert!(buffer[frames..].iter().all(|&x| x == 0.0));
+        assert_eq!(0, voice.process(&mut buffer));
+        assert!(buffer.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn release_extends_past_note() {
+        let synth = Instrument::new(Simple::square(), ASR::new(0.01, 1.0, 0.2));
+        let note = note![A: C4, 1 / 16];
+        let held = crate::samples(note.secs(90.0), 44100);
+        let tail = crate::samples(0.2, 44100);
+
+        let sound = synth.play(90.0, note, 1.0);
+        assert_eq!(crate::samples(note.secs(90.0) + 0.2, 44100), sound.len());
+        assert!(sound[held..held + tail / 2].iter().any(|x| x.abs() > 0.4));
+        assert!(sound[held + tail / 2..].iter().all(|x| x.abs() <= 0.5));
+    }
+
+    #[test]
+    fn held_voice_sounds_until_note_off() {
+        let synth = Instrument::new(Simple::square(), ASR::new(0.0, 10.0, 0.1));
+        let mut voice = synth.note_on(note![A: C4, 1 / 64], 1.0);
+        let mut buffer = vec![0.0; 4410];
+
+        for _ in 0..20 {
+            assert_eq!(buffer.len(), voice.process(&mut buffer));
+        }
+        assert!(!voice.is_finished());
+
+        voice.note_off();
+        assert!(voice.is_released());
+        let tail = drain(&mut voice, 1000);
+        assert_eq!(4410, tail.len());
+        assert!(tail[tail.len() - 10..].iter().all(|x| x.abs() < 0.01));
+    }
+
+    #[test]
+    fn rar_voice_fades_after_early_note_off() {
+        let synth = Instrument::new(Simple::square(), RAR::new(0.01, 0.2));
+        let mut voice = synth.note_on(note![A: C4, 1 / 64], 1.0);
+        let mut buffer = vec![0.0; 2205];
+        voice.process(&mut buffer);
+
+        voice.note_off();
+        let tail = drain(&mut voice, 1000);
+        assert_eq!(crate::samples(0.2, 44100), tail.len());
+        assert!(tail[..100].iter().any(|x| x.abs() > 0.5));
+        assert!(tail[tail.len() - 10..].iter().all(|x| x.abs() < 0.01));
+    }
+
+    #[test]
+    fn mix_blocks_match_whole_note() {
+        let mut rack = Rack::default();
+        rack.add(Instrument::new(Simple::default(), RAR::new(0.05, 0.05)));
+        rack.add_with_volume(Instrument::new(Simple::square(), RAR::new(0.1, 0.0)), 0.5);
+        let note = note![A: C4, 1 / 16];
+
+        let whole = rack.play(90.0, note, 1.0);
+        let mut mix = rack.stream(90.0, note, 1.0);
+        assert_eq!(whole, drain(&mut mix, 256));
+    }
+
+    #[test]
+    fn effect_tails_extend_output() {
+        let note = note![A: C4, 1 / 16];
+        let dry = Instrument::new(Simple::square(), RAR::new(0.01, 0.05));
+        let wet = Instrument::new(Simple::square(), RAR::new(0.01, 0.05))
+            .with_effect(Delay::new(0.1, 0.5));
+        let length = dry.play(90.0, note, 1.0).len();
+
+        let whole = wet.play(90.0, note, 1.0);
+        assert!(whole.len() > length);
+        assert!(whole[length..].iter().any(|x| x.abs() > 0.1));
+        assert_eq!(whole, drain(&mut wet.voice(90.0, note, 1.0), 300));
+
+        let mut rack = Rack::default();
+        rack.add(wet);
+        rack.add_effect(Reverb::new(0.5, 0.5));
+        let whole = rack.play(90.0, note, 1.0);
+        assert!(whole.len() > crate::samples(1.0, 44100) + length);
+        assert_eq!(whole, drain(&mut rack.stream(90.0, note, 1.0), 300));
+    }
+
+    #[test]
+    fn stereo_voice_pans_mono_sound() {
+        use crate::modulation::{Destination, Source};
+
+        let synth = Instrument::new(Simple::square(), RAR::new(0.01, 0.05));
+        let note = note![A: C4, 1 / 16];
+        let mono = synth.play(90.0, note, 1.0);
+        let (left, right) = synth.play_stereo(90.0, note, 1.0).split();
+        let centre = crate::stereo::pan(0.0).0;
+        assert_eq!(mono.len(), left.len());
+        for ((x, l), r) in mono.iter().zip(left.iter()).zip(right.iter()) {
+            assert!((x * centre - l).abs() < 1e-9 && (l - r).abs() < 1e-9);
+        }
+
+        let wobble = Instrument::new(Simple::square(), crate::envelope::Fixed {}).with_modulation(
+            Source::lfo(LFO::sine(10.0)),
+            Destination::Pan,
+            1.0,
+        );
+        let sound = wobble.play_stereo(90.0, note, 1.0);
+        assert!(sound
+            .left
+            .iter()
+            .zip(sound.right.iter())
+            .any(|(l, r)| l.abs() > r.abs() + 0.1));
+        assert!(sound
+            .left
+            .iter()
+            .zip(sound.right.iter())
+            .any(|(l, r)| r.abs() > l.abs() + 0.1));
+    }
+
+    #[test]
+    fn rack_pans_instruments() {
+        let mut rack = Rack::default();
+        let sine = Instrument::new(Simple::default(), RAR::new(0.05, 0.05));
+        rack.add_with_pan(sine, 1.0, -1.0);
+        let square = Instrument::new(Simple::square(), crate::envelope::Fixed {});
+        rack.add_with_pan(square, 0.5, 1.0);
+        rack.add_effect(Delay::new(0.05, 0.0).with_mix(0.0));
+        let note = note![A: C4, 1 / 16];
+
+        let sound = rack.play_stereo(90.0, note, 1.0);
+        assert_eq!(rack.play(90.0, note, 1.0).len(), sound.len());
+        // Only the square makes it to the right
+        assert!(sound.right[..crate::samples(0.1, 44100)]
+            .iter()
+            .all(|x| (x.abs() - 0.5).abs() < 1e-9 || x.abs() < 1e-9));
+        assert!(sound.left.iter().any(|x| x.abs() > 0.1));
+    }
+}